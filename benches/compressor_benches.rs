@@ -1,6 +1,6 @@
 use compressor::application::parser::TokenParser;
 use compressor::encoding::huffman::tree::HuffmanTree;
-use compressor::encoding::huffman::HuffmanEncoder;
+use compressor::encoding::huffman::{HuffmanDecoder, HuffmanEncoder};
 use criterion::{criterion_group, criterion_main, Criterion};
 
 use std::fs::File;
@@ -11,30 +11,84 @@ fn compressor_benchmark(c: &mut Criterion) {
 
     let str_token_test_data = File::open("./test_data/Ulysses.txt").unwrap();
     let str_reader = BufReader::new(&str_token_test_data);
-    let str_token_input = TokenParser::words_from_reader(str_reader);
+    let str_token_input = TokenParser::words_from_reader(str_reader).unwrap();
 
     let char_token_test_data = File::open("./test_data/Ulysses.txt").unwrap();
     let char_reader = BufReader::new(&char_token_test_data);
-    let char_token_input = TokenParser::chars_from_reader(char_reader);
+    let char_token_input = TokenParser::chars_from_reader(char_reader).unwrap();
 
     group.bench_function("word tokens", |b| {
         b.iter(|| {
-            let tree = HuffmanTree::from_frequencies(&str_token_input.token_frequencies);
+            let tree = HuffmanTree::from_frequencies(&str_token_input.token_frequencies)
+                .unwrap()
+                .unwrap();
             let encoder = HuffmanEncoder::from_huffman_tree(tree);
-            encoder.encode(&str_token_input.lines, |line| {
-                line.split_inclusive(' ').map(String::from)
-            });
+            encoder
+                .encode(&str_token_input.lines, |line| {
+                    line.split_inclusive(' ').map(String::from)
+                })
+                .unwrap();
         })
     });
 
     group.bench_function("char tokens", |b| {
         b.iter(|| {
-            let tree = HuffmanTree::from_frequencies(&char_token_input.token_frequencies);
+            let tree = HuffmanTree::from_frequencies(&char_token_input.token_frequencies)
+                .unwrap()
+                .unwrap();
             let encoder = HuffmanEncoder::from_huffman_tree(tree);
-            encoder.encode(&str_token_input.lines, |line| line.chars());
+            encoder
+                .encode(&str_token_input.lines, |line| line.chars())
+                .unwrap();
         })
     });
 }
 
-criterion_group!(benches, compressor_benchmark);
+fn decompressor_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("Decode text, using");
+
+    let str_token_test_data = File::open("./test_data/Ulysses.txt").unwrap();
+    let str_reader = BufReader::new(&str_token_test_data);
+    let str_token_input = TokenParser::words_from_reader(str_reader).unwrap();
+    let str_tree = HuffmanTree::from_frequencies(&str_token_input.token_frequencies)
+        .unwrap()
+        .unwrap();
+    let str_encoder = HuffmanEncoder::from_huffman_tree(str_tree);
+    let str_decoder = HuffmanDecoder::from_codebook(str_encoder.codebook.clone());
+    let str_encoded = str_encoder
+        .encode(&str_token_input.lines, |line| {
+            line.split_inclusive(' ').map(String::from)
+        })
+        .unwrap();
+
+    let char_token_test_data = File::open("./test_data/Ulysses.txt").unwrap();
+    let char_reader = BufReader::new(&char_token_test_data);
+    let char_token_input = TokenParser::chars_from_reader(char_reader).unwrap();
+    let char_tree = HuffmanTree::from_frequencies(&char_token_input.token_frequencies)
+        .unwrap()
+        .unwrap();
+    let char_encoder = HuffmanEncoder::from_huffman_tree(char_tree);
+    let char_decoder = HuffmanDecoder::from_codebook(char_encoder.codebook.clone());
+    let char_encoded = char_encoder
+        .encode(&char_token_input.lines, |line| line.chars())
+        .unwrap();
+
+    group.bench_function("word tokens", |b| {
+        b.iter(|| {
+            str_decoder.decode(&str_encoded, |tokens: Vec<String>| {
+                tokens.join("").into_bytes()
+            })
+        })
+    });
+
+    group.bench_function("char tokens", |b| {
+        b.iter(|| {
+            char_decoder.decode(&char_encoded, |tokens: Vec<char>| {
+                tokens.into_iter().collect::<String>().into_bytes()
+            })
+        })
+    });
+}
+
+criterion_group!(benches, compressor_benchmark, decompressor_benchmark);
 criterion_main!(benches);
@@ -1,36 +1,239 @@
+use crate::encoding::huffman::encoder::{DEFAULT_MAX_CODE_LENGTH, MAX_CODE_LENGTH};
+use crate::models::Model;
 pub use clap::Parser;
-#[derive(clap::ValueEnum, Clone, Debug)]
-pub enum Mode {
-    Compress,
-    Decompress,
-}
 
-#[derive(clap::ValueEnum, Clone, Debug)]
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TokenType {
     Chars,
     Words,
+    /// Raw bytes, for input which is not UTF-8 text.
+    Bytes,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PayloadType {
+    /// Pack every line into a single bit stream.
+    Stream,
+    /// Store each line as a separate, byte aligned bit vector.
+    Lines,
 }
 
 /// A compression and decompression tool.
 ///
-/// The default behaviour is to compress stdin to stdout. Optionally, input and output file paths may be provided.
+/// The default behaviour is to compress stdin to stdout, so `compressor < in.txt > out.huf` is the
+/// same as `compressor compress < in.txt > out.huf`. Optionally, input and output file paths may be
+/// provided.
+///
+/// Like gzip, `compressor FILE...` compresses each file in place to FILE.huf, removing the
+/// original, and `compressor -d FILE.huf...` decompresses them again. See --keep, --force and
+/// --stdout.
 ///
-/// During compression, the text is broken into 'tokens', either chars or words. Depending on the workload, compression ratio and speed may be better for one choice or the other. The default token type is 'chars'.
+/// During compression, the text is broken into 'tokens', either chars or words. Depending on the workload, compression ratio and speed may be better for one choice or the other. The default token type is 'chars'. Input which is not valid UTF-8, such as images or other binaries, can be compressed with the 'bytes' token type.
 ///
-/// To decompress a file, set --mode=decompress and ensure the same token type is selected as was
-/// used in compression.
+/// To decompress a file, use `compressor decompress`. The token type is read from the file header.
+/// To check that files decompress correctly without writing them out, use `compressor test`, and
+/// to describe a compressed file, use `compressor info`.
+///
+/// To compress many small, similar files, train a codebook once with `compressor train`, then pass
+/// it to both compression and decompression with --codebook. For short English texts, the
+/// codebooks built into the compressor can be used with --model instead.
+///
+/// To store many files, or whole directories, in one file, use `compressor archive`, then
+/// `compressor list` and `compressor extract` to see and restore what it holds.
 ///
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about)]
+#[command(
+    author,
+    version,
+    about,
+    long_about,
+    args_conflicts_with_subcommands = true
+)]
 pub struct Args {
-    #[arg(short, long, default_value_t = Mode::Compress)]
-    #[clap(value_enum)]
-    pub mode: Mode,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Options for compression, when no subcommand is given.
+    #[command(flatten)]
+    pub compress: CompressArgs,
+}
+
+impl Args {
+    /// The subcommand to run, which is `compress` if none was given.
+    pub fn subcommand(self) -> Command {
+        self.command.unwrap_or(Command::Compress(self.compress))
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Compress a file, or stdin. This is the default when no subcommand is given.
+    Compress(CompressArgs),
+    /// Decompress a file, or stdin.
+    Decompress(DecompressArgs),
+    /// Check that compressed files decode correctly, without writing them out.
+    ///
+    /// Each file is decoded, checking its structure and checksums, and the text is discarded.
+    /// Prints one line per file, and fails if any file is damaged.
+    Test(TestArgs),
+    /// Describe a compressed file: its layout, sizes, codebook and checksums.
+    ///
+    /// Only the structure of the file is read, so no text is decoded.
+    Info(InfoArgs),
+    /// Count tokens across the given files and save a codebook.
+    ///
+    /// The codebook can be shared by many compressed files with --codebook.
+    Train(TrainArgs),
+    /// Count the tokens in a file, or stdin, and show how well they would compress.
+    Stats(StatsArgs),
+    /// Compress files and whole directories into one archive.
+    ///
+    /// Directories are walked recursively. Each file is compressed on its own, and its path,
+    /// size, modification time and permissions are kept in the index of the archive. Symbolic
+    /// links are skipped.
+    Archive(ArchiveArgs),
+    /// Restore the files and directories in an archive, or only the named entries.
+    Extract(ExtractArgs),
+    /// List the files and directories in an archive.
+    List(ListArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CompressArgs {
+    #[command(flatten)]
+    pub options: CompressOptions,
+
+    /// Decompress instead, as `compressor decompress` does.
+    #[arg(short, long)]
+    pub decompress: bool,
+
+    /// With --decompress, skip checking the decoded text against the checksums stored with it.
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// File path of input, otherwise the compressor reads from stdin.
+    #[arg(short, long)]
+    pub in_file: Option<String>,
+
+    /// File path of output, otherwise the compressor writes to stdout.
+    #[arg(short, long)]
+    pub out_file: Option<String>,
 
+    #[command(flatten)]
+    pub files: FileArgs,
+}
+
+/// How to compress each input, shared by `compress` and `archive`.
+#[derive(clap::Args, Clone, Debug)]
+pub struct CompressOptions {
     #[arg(short, long, default_value_t = TokenType::Chars)]
     #[clap(value_enum)]
     pub token_type: TokenType,
 
+    /// Layout of the compressed text.
+    #[arg(short, long, default_value_t = PayloadType::Stream)]
+    #[clap(value_enum)]
+    pub payload: PayloadType,
+
+    /// Compress without holding the whole input in memory, writing the output as it is encoded.
+    /// Input files are read in two passes. Stdin is split into blocks of --block-size bytes, each
+    /// with its own codebook. Nothing is written for a block until it fills or the input ends, so
+    /// for a slow stream such as `tail -f`, use a smaller --block-size to see output sooner.
+    #[arg(short, long)]
+    pub streaming: bool,
+
+    /// Approximate size in bytes of each independently compressed block, when streaming from stdin.
+    /// Each block is written and flushed as soon as it fills.
+    #[arg(short, long, default_value_t = 1 << 20)]
+    pub block_size: usize,
+
+    /// Longest code, in bits, that compression may assign to a token. Raised automatically if
+    /// there are too many distinct tokens to fit.
+    #[arg(long, default_value_t = DEFAULT_MAX_CODE_LENGTH)]
+    #[arg(value_parser = clap::value_parser!(u8).range(1..=i64::from(MAX_CODE_LENGTH)))]
+    pub max_code_length: u8,
+
+    /// Shared codebook file, written with `compressor train`. Compressed files only refer to the
+    /// codebook by its ID, so the same file must be given to decompress them. The token type is
+    /// read from the codebook.
+    #[arg(long)]
+    pub codebook: Option<String>,
+
+    /// Compress with a codebook built into the compressor, which suits short English texts. Files
+    /// compressed with a model are decompressed without any extra options.
+    #[arg(long, conflicts_with = "codebook")]
+    #[clap(value_enum)]
+    pub model: Option<Model>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ArchiveArgs {
+    #[command(flatten)]
+    pub options: CompressOptions,
+
+    /// File path of the archive, otherwise the compressor writes to stdout.
+    #[arg(short, long)]
+    pub out_file: Option<String>,
+
+    /// Compress every file with one codebook, built from the tokens of all of them and stored
+    /// once in the archive. This suits many small, similar files, such as source code or JSON
+    /// documents, whose codebooks would otherwise outweigh their text.
+    #[arg(long, conflicts_with_all = ["codebook", "model"])]
+    pub solid: bool,
+
+    /// Files and directories to archive. Each is stored under its own name, so `docs/a.txt` is
+    /// stored as `a.txt` inside `docs`.
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ExtractArgs {
+    /// Shared codebook file the archive was compressed with, if any.
+    #[arg(long)]
+    pub codebook: Option<String>,
+
+    /// Skip checking the decoded files against the checksums stored with them.
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Directory to extract into, otherwise the current directory.
+    #[arg(short = 'C', long, default_value = ".")]
+    pub directory: String,
+
+    /// Write the files to stdout, one after another, rather than restoring them.
+    #[arg(short = 'c', long)]
+    pub stdout: bool,
+
+    /// Overwrite files which already exist.
+    #[arg(short, long)]
+    pub force: bool,
+
+    /// Archive to extract from.
+    pub archive: String,
+
+    /// Entries to extract, as listed by `compressor list`, otherwise all of them. Naming a
+    /// directory extracts everything in it. Names may use `*` and `?`, which don't match `/`.
+    pub entries: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ListArgs {
+    /// Archive to list.
+    pub archive: String,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DecompressArgs {
+    /// Shared codebook file the input was compressed with, if any.
+    #[arg(long)]
+    pub codebook: Option<String>,
+
+    /// Skip checking the decoded text against the checksums stored with it. This is a little
+    /// faster, but damage to the file may go unnoticed.
+    #[arg(long)]
+    pub no_verify: bool,
+
     /// File path of input, otherwise the compressor reads from stdin.
     #[arg(short, long)]
     pub in_file: Option<String>,
@@ -38,4 +241,146 @@ pub struct Args {
     /// File path of output, otherwise the compressor writes to stdout.
     #[arg(short, long)]
     pub out_file: Option<String>,
+
+    #[command(flatten)]
+    pub files: FileArgs,
+}
+
+impl From<CompressArgs> for DecompressArgs {
+    /// The decompression options of `compressor -d`.
+    fn from(args: CompressArgs) -> Self {
+        DecompressArgs {
+            codebook: args.options.codebook,
+            no_verify: args.no_verify,
+            in_file: args.in_file,
+            out_file: args.out_file,
+            files: args.files,
+        }
+    }
+}
+
+/// Files to compress or decompress in place, as gzip does.
+#[derive(clap::Args, Debug)]
+pub struct FileArgs {
+    /// Files to compress to FILE.huf, or decompress from FILE.huf, in place. Each file is removed
+    /// once its output has been written, unless --keep is given.
+    #[arg(conflicts_with_all = ["in_file", "out_file"])]
+    pub files: Vec<String>,
+
+    /// Write the output of every file to stdout, and keep the files.
+    #[arg(short = 'c', long)]
+    pub stdout: bool,
+
+    /// Keep the input files, rather than removing them.
+    #[arg(short, long)]
+    pub keep: bool,
+
+    /// Overwrite output files which already exist.
+    #[arg(short, long)]
+    pub force: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TestArgs {
+    /// Shared codebook file the files were compressed with, if any.
+    #[arg(long)]
+    pub codebook: Option<String>,
+
+    /// Check only the structure of the files, not their checksums.
+    #[arg(long)]
+    pub no_verify: bool,
+
+    /// Files to check, otherwise the compressor reads from stdin.
+    pub files: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct InfoArgs {
+    /// Shared codebook file the file was compressed with, to describe it as well.
+    #[arg(long)]
+    pub codebook: Option<String>,
+
+    /// File to describe, otherwise the compressor reads from stdin.
+    pub file: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TrainArgs {
+    #[arg(short, long, default_value_t = TokenType::Chars)]
+    #[clap(value_enum)]
+    pub token_type: TokenType,
+
+    /// Longest code, in bits, that the codebook may assign to a token.
+    #[arg(long, default_value_t = DEFAULT_MAX_CODE_LENGTH)]
+    #[arg(value_parser = clap::value_parser!(u8).range(1..=i64::from(MAX_CODE_LENGTH)))]
+    pub max_code_length: u8,
+
+    /// Keep only this many of the most common tokens in the codebook. The rest are escaped when
+    /// compressing.
+    #[arg(long)]
+    pub max_tokens: Option<usize>,
+
+    /// File path of the codebook, otherwise the compressor writes to stdout.
+    #[arg(short, long)]
+    pub out_file: Option<String>,
+
+    /// Files to count tokens in, otherwise the compressor reads from stdin.
+    pub files: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct StatsArgs {
+    #[arg(short, long, default_value_t = TokenType::Chars)]
+    #[clap(value_enum)]
+    pub token_type: TokenType,
+
+    /// Longest code, in bits, to assign to a token.
+    #[arg(long, default_value_t = DEFAULT_MAX_CODE_LENGTH)]
+    #[arg(value_parser = clap::value_parser!(u8).range(1..=i64::from(MAX_CODE_LENGTH)))]
+    pub max_code_length: u8,
+
+    /// Number of the most common tokens to list.
+    #[arg(long, default_value_t = 10)]
+    pub top: usize,
+
+    /// File to count tokens in, otherwise the compressor reads from stdin.
+    pub file: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    #[test]
+    fn test_cli_is_consistent() {
+        Args::command().debug_assert();
+    }
+
+    #[test]
+    fn test_compress_is_the_default() {
+        let args = Args::parse_from(["compressor", "-t", "words", "-o", "out.huf"]);
+        let Command::Compress(args) = args.subcommand() else {
+            panic!("expected compress");
+        };
+        assert_eq!(args.options.token_type, TokenType::Words);
+        assert_eq!(args.out_file.as_deref(), Some("out.huf"));
+
+        let args = Args::parse_from(["compressor", "decompress", "-i", "in.huf"]);
+        assert!(matches!(args.subcommand(), Command::Decompress(_)));
+        // After an option of the default command, a subcommand name is a file to compress.
+        let args = Args::parse_from(["compressor", "-k", "decompress"]);
+        let Command::Compress(args) = args.subcommand() else {
+            panic!("expected compress");
+        };
+        assert_eq!(args.files.files, ["decompress"]);
+
+        let args = Args::parse_from(["compressor", "-d", "-k", "a.huf"]);
+        let Command::Compress(args) = args.subcommand() else {
+            panic!("expected compress");
+        };
+        let args = DecompressArgs::from(args);
+        assert!(args.files.keep);
+        assert_eq!(args.files.files, ["a.huf"]);
+    }
 }
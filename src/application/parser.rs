@@ -1,63 +1,275 @@
+use crate::Error;
 use std::collections::HashMap;
-use std::io::BufRead;
+use std::{
+    fmt::{self, Display},
+    hash::Hash,
+    io::{self, BufRead},
+};
 
 /// Parses input from implementor of BufRead.
-/// Computes frequency of tokens (chars / words) and stores entire input in memory.
-pub struct TokenParser<T> {
-    pub lines: Vec<String>,
-    pub token_frequencies: HashMap<T, u32>,
+/// Computes frequency of tokens (chars / words / bytes) and stores entire input in memory.
+///
+/// Text is stored as `String` lines. Bytes are stored as `Vec<u8>` chunks, as returned by
+/// `BufRead::fill_buf`, since the input need not be valid UTF-8.
+pub struct TokenParser<T, L = String> {
+    pub lines: Vec<L>,
+    pub token_frequencies: HashMap<T, u64>,
 }
 
 impl TokenParser<char> {
     /// Parse input into `Vec<String>` lines, and compute frequency of each char in the input.
-    pub fn chars_from_reader<R: BufRead>(mut reader: R) -> Self {
+    pub fn chars_from_reader<R: BufRead>(reader: R) -> Result<Self, Error> {
         let mut token_frequencies = HashMap::new();
-        let mut line = String::new();
         let mut lines = Vec::new();
-        while let Ok(n_bytes) = reader.read_line(&mut line) {
-            if n_bytes == 0 {
-                break;
-            }
+        for line in read_lines(reader) {
+            let line = line?;
             for c in line.chars() {
                 token_frequencies
                     .entry(c)
                     .and_modify(|value| *value += 1)
                     .or_insert(1);
             }
-            lines.push(line.clone());
-            line.clear();
+            lines.push(line);
         }
 
-        TokenParser {
+        Ok(TokenParser {
             lines,
             token_frequencies,
+        })
+    }
+
+    /// Compute the frequency of each char in the input, without keeping the input in memory.
+    pub fn char_frequencies<R: BufRead>(reader: R) -> Result<HashMap<char, u64>, Error> {
+        let mut token_frequencies = HashMap::new();
+        for line in read_lines(reader) {
+            for c in line?.chars() {
+                *token_frequencies.entry(c).or_insert(0) += 1;
+            }
         }
+        Ok(token_frequencies)
     }
 }
 
 impl TokenParser<String> {
     /// Parse input into `Vec<String>` lines, and compute frequency of each word in the input.
-    pub fn words_from_reader<R: BufRead>(mut reader: R) -> Self {
+    pub fn words_from_reader<R: BufRead>(reader: R) -> Result<Self, Error> {
         let mut token_frequencies = HashMap::new();
-        let mut line = String::new();
         let mut lines = Vec::new();
-        while let Ok(n_bytes) = reader.read_line(&mut line) {
-            if n_bytes == 0 {
-                break;
-            }
+        for line in read_lines(reader) {
+            let line = line?;
             for word in line.split_inclusive(' ') {
                 token_frequencies
                     .entry(String::from(word))
                     .and_modify(|value| *value += 1)
                     .or_insert(1);
             }
-            lines.push(line.clone());
-            line.clear();
+            lines.push(line);
         }
 
-        TokenParser {
+        Ok(TokenParser {
             lines,
             token_frequencies,
+        })
+    }
+
+    /// Compute the frequency of each word in the input, without keeping the input in memory.
+    pub fn word_frequencies<R: BufRead>(reader: R) -> Result<HashMap<String, u64>, Error> {
+        let mut token_frequencies = HashMap::new();
+        for line in read_lines(reader) {
+            for word in line?.split_inclusive(' ') {
+                *token_frequencies.entry(String::from(word)).or_insert(0) += 1;
+            }
+        }
+        Ok(token_frequencies)
+    }
+}
+
+impl TokenParser<u8, Vec<u8>> {
+    /// Parse input into `Vec<u8>` chunks, and compute frequency of each byte in the input.
+    pub fn bytes_from_reader<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut token_frequencies = HashMap::new();
+        let mut lines = Vec::new();
+        for chunk in read_chunks(reader) {
+            let chunk = chunk?;
+            for &byte in &chunk {
+                token_frequencies
+                    .entry(byte)
+                    .and_modify(|value| *value += 1)
+                    .or_insert(1);
+            }
+            lines.push(chunk);
+        }
+
+        Ok(TokenParser {
+            lines,
+            token_frequencies,
+        })
+    }
+
+    /// Compute the frequency of each byte in the input, without keeping the input in memory.
+    pub fn byte_frequencies<R: BufRead>(reader: R) -> Result<HashMap<u8, u64>, Error> {
+        let mut token_frequencies = HashMap::new();
+        for chunk in read_chunks(reader) {
+            for byte in chunk? {
+                *token_frequencies.entry(byte).or_insert(0) += 1;
+            }
+        }
+        Ok(token_frequencies)
+    }
+}
+
+/// Add the token counts of `other` to `frequencies`, e.g. to count tokens across several inputs.
+pub fn merge_frequencies<T: Hash + Eq>(
+    frequencies: &mut HashMap<T, u64>,
+    other: HashMap<T, u64>,
+) -> Result<(), Error> {
+    for (token, count) in other {
+        let total = frequencies.entry(token).or_insert(0);
+        *total = total.checked_add(count).ok_or(Error::CountOverflow)?;
+    }
+    Ok(())
+}
+
+/// Sum of each token's count times its `weight`, e.g. its length or code length. Fails with
+/// `Error::CountOverflow` if the total does not fit in a `u64`.
+pub fn weighted_total<T>(
+    frequencies: &HashMap<T, u64>,
+    weight: impl Fn(&T) -> u64,
+) -> Result<u64, Error> {
+    frequencies.iter().try_fold(0u64, |total, (token, &count)| {
+        count
+            .checked_mul(weight(token))
+            .and_then(|weighted| total.checked_add(weighted))
+            .ok_or(Error::CountOverflow)
+    })
+}
+
+/// Keep only the `max_tokens` most frequent tokens, e.g. to limit the vocabulary of a trained
+/// codebook. Ties are broken by token order, so the same counts always keep the same tokens.
+///
+/// Also returns the total count of the tokens which were left out, saturating at `u64::MAX`.
+pub fn most_frequent<T: Hash + Eq + Ord>(
+    frequencies: HashMap<T, u64>,
+    max_tokens: usize,
+) -> (HashMap<T, u64>, u64) {
+    let mut tokens: Vec<(T, u64)> = frequencies.into_iter().collect();
+    tokens.sort_unstable_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    let dropped = tokens
+        .drain(max_tokens.min(tokens.len())..)
+        .fold(0_u64, |total, (_, count)| total.saturating_add(count));
+    (tokens.into_iter().collect(), dropped)
+}
+
+/// Iterate over the lines of the input, keeping the line endings.
+///
+/// Iteration stops after the first error, which records where in the input it happened.
+pub fn read_lines<R: BufRead>(mut reader: R) -> impl Iterator<Item = Result<String, Error>> {
+    let mut location = Location::default();
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let mut bytes = Vec::new();
+        let result = match reader.read_until(b'\n', &mut bytes) {
+            Ok(0) => return None,
+            Ok(_) => String::from_utf8(bytes).map_err(|e| {
+                location.error_at(e.utf8_error().valid_up_to(), ParseErrorKind::InvalidUtf8)
+            }),
+            Err(e) => Err(location.error_at(bytes.len(), ParseErrorKind::Io(e))),
+        };
+        match &result {
+            Ok(line) => location.advance(line.as_bytes()),
+            Err(_) => failed = true,
+        }
+        Some(result)
+    })
+}
+
+/// Iterate over the input in chunks of bytes, as returned by `BufRead::fill_buf`.
+///
+/// Iteration stops after the first error, which records where in the input it happened.
+pub fn read_chunks<R: BufRead>(mut reader: R) -> impl Iterator<Item = Result<Vec<u8>, Error>> {
+    let mut location = Location::default();
+    let mut failed = false;
+    std::iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        let result = loop {
+            match reader.fill_buf() {
+                Ok([]) => return None,
+                Ok(chunk) => break Ok(chunk.to_vec()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => break Err(location.error_at(0, ParseErrorKind::Io(e))),
+            }
+        };
+        match &result {
+            Ok(chunk) => {
+                reader.consume(chunk.len());
+                location.advance(chunk);
+            }
+            Err(_) => failed = true,
+        }
+        Some(result)
+    })
+}
+
+/// Position of the reader within the input.
+#[derive(Default)]
+struct Location {
+    byte_offset: u64,
+    newlines: u64,
+}
+
+impl Location {
+    fn advance(&mut self, bytes: &[u8]) {
+        self.byte_offset += bytes.len() as u64;
+        self.newlines += bytes.iter().filter(|&&b| b == b'\n').count() as u64;
+    }
+
+    /// Error for a failure `offset` bytes past the current position, which must be on the current
+    /// line.
+    fn error_at(&self, offset: usize, kind: ParseErrorKind) -> Error {
+        Error::Parse(ParseError {
+            byte_offset: self.byte_offset + offset as u64,
+            line: self.newlines + 1,
+            kind,
+        })
+    }
+}
+
+/// Failure to read the input, with the location at which it happened.
+#[derive(Debug)]
+pub struct ParseError {
+    /// Offset of the first byte which could not be read or decoded, from the start of the input.
+    pub byte_offset: u64,
+    /// Line on which the failure happened, counting from 1.
+    pub line: u64,
+    pub kind: ParseErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ParseErrorKind {
+    Io(io::Error),
+    InvalidUtf8,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::Io(e) => write!(f, "failed to read input: {e}")?,
+            ParseErrorKind::InvalidUtf8 => write!(f, "input is not valid UTF-8")?,
+        }
+        write!(f, " (line {}, byte offset {})", self.line, self.byte_offset)
+    }
+}
+
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ParseErrorKind::Io(e) => Some(e),
+            ParseErrorKind::InvalidUtf8 => None,
         }
     }
 }
@@ -66,10 +278,17 @@ impl TokenParser<String> {
 mod tests {
     use super::*;
 
+    fn parse_error<T>(result: Result<T, Error>) -> ParseError {
+        match result {
+            Err(Error::Parse(error)) => error,
+            _ => panic!("expected a parse error"),
+        }
+    }
+
     #[test]
     fn test_process_as_chars() {
         let lines_text = b"Hello world!\nGoodbye :(";
-        let char_summary = TokenParser::chars_from_reader(&lines_text[..]);
+        let char_summary = TokenParser::chars_from_reader(&lines_text[..]).unwrap();
         assert_eq!(char_summary.lines, vec!["Hello world!\n", "Goodbye :("]);
         assert_eq!(char_summary.token_frequencies[&'H'], 1);
         assert_eq!(char_summary.token_frequencies[&'e'], 2);
@@ -91,9 +310,133 @@ mod tests {
     #[test]
     fn test_process_as_strings() {
         let lines_text = b"Hello world! \n Hello ";
-        let str_summary = TokenParser::words_from_reader(&lines_text[..]);
+        let str_summary = TokenParser::words_from_reader(&lines_text[..]).unwrap();
         assert_eq!(str_summary.lines, vec!["Hello world! \n", " Hello "]);
         assert_eq!(str_summary.token_frequencies["Hello "], 2);
         assert_eq!(str_summary.token_frequencies["world! "], 1);
     }
+
+    #[test]
+    fn test_frequencies_match_parsed_input() {
+        let lines_text = b"Hello world! \n Hello ";
+        assert_eq!(
+            TokenParser::char_frequencies(&lines_text[..]).unwrap(),
+            TokenParser::chars_from_reader(&lines_text[..])
+                .unwrap()
+                .token_frequencies
+        );
+        assert_eq!(
+            TokenParser::word_frequencies(&lines_text[..]).unwrap(),
+            TokenParser::words_from_reader(&lines_text[..])
+                .unwrap()
+                .token_frequencies
+        );
+    }
+
+    #[test]
+    fn test_process_as_bytes() {
+        let bytes = b"caf\xc3\xa9 \xff\xfe\n\x00";
+        let byte_summary = TokenParser::bytes_from_reader(&bytes[..]).unwrap();
+        assert_eq!(byte_summary.lines.concat(), bytes);
+        assert_eq!(byte_summary.token_frequencies[&b'c'], 1);
+        assert_eq!(byte_summary.token_frequencies[&0xc3], 1);
+        assert_eq!(byte_summary.token_frequencies[&0xff], 1);
+        assert_eq!(byte_summary.token_frequencies[&0x00], 1);
+        assert_eq!(
+            TokenParser::byte_frequencies(&bytes[..]).unwrap(),
+            byte_summary.token_frequencies
+        );
+    }
+
+    #[test]
+    fn test_merge_frequencies() {
+        let mut frequencies = HashMap::from([('a', 2), ('b', 1)]);
+        merge_frequencies(&mut frequencies, HashMap::from([('b', 3), ('c', 1)])).unwrap();
+        assert_eq!(frequencies, HashMap::from([('a', 2), ('b', 4), ('c', 1)]));
+
+        let overflow = merge_frequencies(&mut frequencies, HashMap::from([('a', u64::MAX)]));
+        assert!(overflow.is_err());
+    }
+
+    #[test]
+    fn test_weighted_total() {
+        let frequencies = HashMap::from([(String::from("ab"), 3), (String::from("c"), 2)]);
+        assert_eq!(weighted_total(&frequencies, |_| 1).unwrap(), 5);
+        assert_eq!(
+            weighted_total(&frequencies, |token| token.len() as u64).unwrap(),
+            8
+        );
+        assert_eq!(
+            weighted_total(&HashMap::<char, u64>::new(), |_| 1).unwrap(),
+            0
+        );
+
+        // The product of a count and its weight, or the sum of the products, may not fit.
+        let frequencies = HashMap::from([('a', u64::MAX / 2 + 1)]);
+        assert!(matches!(
+            weighted_total(&frequencies, |_| 2),
+            Err(Error::CountOverflow)
+        ));
+        let frequencies = HashMap::from([('a', u64::MAX), ('b', 1)]);
+        assert!(matches!(
+            weighted_total(&frequencies, |_| 1),
+            Err(Error::CountOverflow)
+        ));
+    }
+
+    #[test]
+    fn test_most_frequent() {
+        let frequencies = HashMap::from([('a', 1), ('b', 5), ('c', 2), ('d', 2), ('e', 1)]);
+        let (kept, dropped) = most_frequent(frequencies.clone(), 2);
+        assert_eq!(kept, HashMap::from([('b', 5), ('c', 2)]));
+        assert_eq!(dropped, 4);
+
+        assert_eq!(most_frequent(frequencies.clone(), 10), (frequencies, 0));
+    }
+
+    #[test]
+    fn test_lines_keep_line_endings() {
+        let lines_text = b"Hello world!\r\nGoodbye :(\n\n";
+        let lines: Vec<String> = read_lines(&lines_text[..]).map(Result::unwrap).collect();
+        assert_eq!(lines, vec!["Hello world!\r\n", "Goodbye :(\n", "\n"]);
+    }
+
+    #[test]
+    fn test_invalid_utf8_reports_location() {
+        let lines_text = b"Hello world!\nGood\xffbye :(\nNever read";
+        let error = parse_error(TokenParser::chars_from_reader(&lines_text[..]));
+        assert!(matches!(error.kind, ParseErrorKind::InvalidUtf8));
+        assert_eq!(error.line, 2);
+        assert_eq!(error.byte_offset, 17);
+
+        let error = parse_error(TokenParser::word_frequencies(&lines_text[..]));
+        assert_eq!((error.line, error.byte_offset), (2, 17));
+    }
+
+    #[test]
+    fn test_read_error_reports_location() {
+        /// Reader which fails after returning some of its input.
+        struct FailingReader(&'static [u8]);
+
+        impl io::Read for FailingReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if self.0.is_empty() {
+                    return Err(io::Error::other("disk on fire"));
+                }
+                let n = self.0.len().min(buf.len());
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
+        let reader = io::BufReader::new(FailingReader(b"one\ntwo\nthr"));
+        let error = parse_error(TokenParser::chars_from_reader(reader));
+        assert!(matches!(error.kind, ParseErrorKind::Io(_)));
+        assert_eq!((error.line, error.byte_offset), (3, 11));
+
+        let reader = io::BufReader::new(FailingReader(b"one\ntwo\nthr"));
+        let error = parse_error(TokenParser::bytes_from_reader(reader));
+        assert_eq!((error.line, error.byte_offset), (3, 11));
+    }
 }
@@ -0,0 +1,850 @@
+use crate::{
+    application::{
+        cli::{CompressOptions, TokenType},
+        parser::{self, ParseErrorKind, TokenParser},
+    },
+    container::{ArchiveHeader, CodebookHeader, ARCHIVE_FLAG_SOLID, ARCHIVE_HEADER_LEN},
+    encoding::huffman::{self, DecodeLimits, EncoderBuilder},
+    file, Error,
+};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    hash::Hash,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use walkdir::WalkDir;
+
+/// Size of the trailer at the end of an archive, which holds the offset of the index.
+pub const TRAILER_LEN: u64 = 8;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// A file or directory stored in an archive, as recorded in its index.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// Path relative to the root of the archive, with `/` between its components.
+    pub path: String,
+    pub kind: EntryKind,
+    /// Size of the original file, in bytes. Zero for a directory.
+    pub size: u64,
+    /// Last modification time, in seconds since the Unix epoch.
+    pub mtime: i64,
+    /// Unix permission bits, e.g. `0o644`.
+    pub mode: u32,
+    /// Offset of the compressed file from the start of the archive. It is a whole compressed file,
+    /// header and all, so it decompresses like any other.
+    pub offset: u64,
+    /// Length of the compressed file, in bytes. Zero for a directory.
+    pub length: u64,
+}
+
+impl Entry {
+    /// Describe a file or directory from its metadata. Where its data lies is filled in by
+    /// `ArchiveWriter::add`.
+    pub fn from_metadata(path: String, metadata: &fs::Metadata) -> Self {
+        let (kind, size) = match metadata.is_dir() {
+            true => (EntryKind::Directory, 0),
+            false => (EntryKind::File, metadata.len()),
+        };
+        Entry {
+            path,
+            kind,
+            size,
+            mtime: metadata.modified().map(unix_time).unwrap_or(0),
+            mode: mode(metadata),
+            offset: 0,
+            length: 0,
+        }
+    }
+
+    /// Where to restore the entry under `root`.
+    ///
+    /// Fails unless every component of the path is a plain name, so that a crafted archive cannot
+    /// write outside `root`, e.g. with `../` or an absolute path.
+    pub fn path_under(&self, root: &Path) -> Result<PathBuf, Error> {
+        let mut path = root.to_path_buf();
+        for name in self.path.split('/') {
+            let mut components = Path::new(name).components();
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(component)), None) if component == name => path.push(name),
+                _ => {
+                    return Err(Error::CorruptData(format!(
+                        "unsafe path {:?} in the archive index",
+                        self.path
+                    )))
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    /// Give a restored file or directory the permissions and modification time of the entry.
+    ///
+    /// Directories should be restored after the files in them, since adding a file changes the
+    /// modification time of its directory, and a read-only directory cannot be added to.
+    pub fn restore_metadata(&self, path: &Path) -> io::Result<()> {
+        let modified = match u64::try_from(self.mtime) {
+            Ok(secs) => UNIX_EPOCH + Duration::from_secs(secs),
+            Err(_) => UNIX_EPOCH - Duration::from_secs(self.mtime.unsigned_abs()),
+        };
+        filetime::set_file_mtime(path, filetime::FileTime::from_system_time(modified))?;
+        fs::set_permissions(path, permissions(self.mode, path)?)
+    }
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &fs::Metadata) -> u32 {
+    match (metadata.is_dir(), metadata.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    }
+}
+
+#[cfg(unix)]
+fn permissions(mode: u32, _path: &Path) -> io::Result<fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+    Ok(fs::Permissions::from_mode(mode & 0o7777))
+}
+
+#[cfg(not(unix))]
+fn permissions(mode: u32, path: &Path) -> io::Result<fs::Permissions> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(mode & 0o200 == 0);
+    Ok(permissions)
+}
+
+/// Writes an archive of many files and directories.
+///
+/// Layout: an `ArchiveHeader`, then the shared codebook of a solid archive, then the compressed
+/// file of each entry back to back, then the MessagePack encoded index of every entry, then the
+/// offset of the index as a little endian `u64`.
+/// The index is written last so that entries can be written as they are compressed, and read back
+/// from the end so that one entry can be extracted without reading the others.
+pub struct ArchiveWriter<W> {
+    writer: W,
+    offset: u64,
+    entries: Vec<Entry>,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(mut writer: W, header: &ArchiveHeader) -> Result<Self, Error> {
+        header.write_to(&mut writer)?;
+        Ok(ArchiveWriter {
+            writer,
+            offset: ARCHIVE_HEADER_LEN as u64,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Start a solid archive, whose files are all compressed with one shared codebook. `codebook`
+    /// is the codebook as a codebook file would hold it, header and all.
+    pub fn solid(mut writer: W, codebook: &[u8]) -> Result<Self, Error> {
+        ArchiveHeader::new().solid().write_to(&mut writer)?;
+        writer.write_all(codebook)?;
+        Ok(ArchiveWriter {
+            writer,
+            offset: (ARCHIVE_HEADER_LEN + codebook.len()) as u64,
+            entries: Vec::new(),
+        })
+    }
+
+    /// Add an entry, with its compressed file. `compressed` is empty for a directory.
+    pub fn add(&mut self, mut entry: Entry, compressed: &[u8]) -> Result<(), Error> {
+        self.writer.write_all(compressed)?;
+        entry.offset = self.offset;
+        entry.length = compressed.len() as u64;
+        self.offset += entry.length;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Write the index, returning the writer.
+    pub fn finish(mut self) -> Result<W, Error> {
+        rmp_serde::encode::write(&mut self.writer, &self.entries)?;
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the index of an archive, and the compressed file of any entry in it.
+pub struct ArchiveReader<R> {
+    reader: R,
+    pub header: ArchiveHeader,
+    entries: Vec<Entry>,
+    /// Where the entries start, after the header and any shared codebook.
+    entries_start: u64,
+}
+
+impl<R: Read + Seek> ArchiveReader<R> {
+    /// Read the header and index of an archive.
+    ///
+    /// The index is limited to `limits.max_body_size` bytes, and every entry must lie between the
+    /// header and the index.
+    pub fn open(mut reader: R, limits: &DecodeLimits) -> Result<Self, Error> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = ArchiveHeader::read_from(&mut reader)?;
+        let end = reader.seek(SeekFrom::End(0))?;
+        let entries_start = ARCHIVE_HEADER_LEN as u64;
+        if end < entries_start + TRAILER_LEN {
+            return Err(Error::InvalidFormat(String::from(
+                "archive is too short to hold an index",
+            )));
+        }
+
+        let index_end = end - TRAILER_LEN;
+        reader.seek(SeekFrom::Start(index_end))?;
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        reader.read_exact(&mut trailer)?;
+        let index_start = u64::from_le_bytes(trailer);
+        if index_start < entries_start || index_start > index_end {
+            return Err(Error::CorruptData(format!(
+                "index offset {index_start} is outside the archive"
+            )));
+        }
+        if index_end - index_start > limits.max_body_size {
+            return Err(Error::InvalidFormat(format!(
+                "index is larger than the limit of {} bytes",
+                limits.max_body_size
+            )));
+        }
+
+        reader.seek(SeekFrom::Start(index_start))?;
+        let entries: Vec<Entry> =
+            rmp_serde::decode::from_read((&mut reader).take(index_end - index_start))?;
+        for entry in &entries {
+            match entry.offset.checked_add(entry.length) {
+                Some(entry_end) if entry.offset >= entries_start && entry_end <= index_start => {}
+                _ => {
+                    return Err(Error::CorruptData(format!(
+                        "entry {:?} lies outside the archive",
+                        entry.path
+                    )))
+                }
+            }
+        }
+        let entries_start = entries
+            .iter()
+            .map(|entry| entry.offset)
+            .min()
+            .unwrap_or(index_start);
+        Ok(ArchiveReader {
+            reader,
+            header,
+            entries,
+            entries_start,
+        })
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Read the shared codebook of a solid archive, in the format of a codebook file.
+    pub fn shared_codebook(&mut self) -> Result<Option<io::Take<&mut R>>, Error> {
+        if self.header.flags & ARCHIVE_FLAG_SOLID == 0 {
+            return Ok(None);
+        }
+        let codebook_start = ARCHIVE_HEADER_LEN as u64;
+        self.reader.seek(SeekFrom::Start(codebook_start))?;
+        Ok(Some(
+            (&mut self.reader).take(self.entries_start - codebook_start),
+        ))
+    }
+
+    /// Read the compressed file of an entry.
+    pub fn open_entry(&mut self, entry: &Entry) -> Result<io::Take<&mut R>, Error> {
+        self.reader.seek(SeekFrom::Start(entry.offset))?;
+        Ok((&mut self.reader).take(entry.length))
+    }
+}
+
+/// Walk the files and directories to archive, in order, describing each one. `skip` is the
+/// archive being written, if it is a file.
+///
+/// Fails if two paths would be stored under the same name, e.g. `a/notes.txt` and
+/// `b/notes.txt`, since only one of them could be extracted.
+pub fn walk_entries(
+    paths: &[impl AsRef<Path>],
+    skip: Option<&Path>,
+) -> Result<Vec<(Entry, PathBuf)>, Error> {
+    let mut entries = Vec::new();
+    let mut seen: HashMap<String, PathBuf> = HashMap::new();
+    for root in paths {
+        let root = root.as_ref();
+        // Entries are named from the root itself, so `docs/a.txt` is stored as `docs/a.txt` when
+        // archiving `docs`, and as `a.txt` when archiving `docs/a.txt`.
+        let base = root.parent().unwrap_or(Path::new(""));
+        for walked in WalkDir::new(root).sort_by_file_name() {
+            let walked = walked.map_err(io::Error::from)?;
+            let path = walked.path();
+            if walked.path_is_symlink() {
+                warn!("{}: symbolic link, skipped", path.display());
+                continue;
+            }
+            if skip.is_some() && fs::canonicalize(path).ok().as_deref() == skip {
+                continue;
+            }
+            let name = entry_name(path.strip_prefix(base).map_err(io::Error::other)?)?;
+            if name.is_empty() {
+                continue;
+            }
+            if let Some(first) = seen.insert(name.clone(), path.to_path_buf()) {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} and {} would both be archived as {name}",
+                        first.display(),
+                        path.display()
+                    ),
+                )));
+            }
+            let entry = Entry::from_metadata(name, &walked.metadata().map_err(io::Error::from)?);
+            entries.push((entry, path.to_path_buf()));
+        }
+    }
+    Ok(entries)
+}
+
+/// Name of an entry, from the plain names in its path joined with `/`.
+fn entry_name(path: &Path) -> Result<String, Error> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        if let Component::Normal(name) = component {
+            let name = name.to_str().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{}: path is not valid UTF-8", path.display()),
+                )
+            })?;
+            names.push(name);
+        }
+    }
+    Ok(names.join("/"))
+}
+
+/// Build the shared codebook of a solid archive from the token frequencies of every file, merged,
+/// and return it in the format of a codebook file. Files which are not UTF-8 text are left out,
+/// and returns `None` if that leaves none.
+pub fn solid_codebook(
+    options: &CompressOptions,
+    entries: &[(Entry, PathBuf)],
+) -> Result<Option<Vec<u8>>, Error> {
+    let files: Vec<&Path> = entries
+        .iter()
+        .filter(|(entry, _)| entry.kind == EntryKind::File)
+        .map(|(_, path)| path.as_path())
+        .collect();
+    info!("Counting tokens in {} files...", files.len());
+    let builder = EncoderBuilder::new().max_code_length(options.max_code_length);
+    let codebook_header = CodebookHeader::new(options.token_type);
+    let mut codebook = Vec::new();
+    let codebook_id = match options.token_type {
+        TokenType::Chars => count_text_files(&files, TokenParser::char_frequencies)?
+            .map(|frequencies| {
+                file::write_codebook(
+                    &codebook_header,
+                    &builder.build(&frequencies)?.codebook,
+                    &mut codebook,
+                )
+            })
+            .transpose()?,
+        TokenType::Words => count_text_files(&files, TokenParser::word_frequencies)?
+            .map(|frequencies| {
+                file::write_codebook(
+                    &codebook_header,
+                    &builder.build(&frequencies)?.codebook,
+                    &mut codebook,
+                )
+            })
+            .transpose()?,
+        TokenType::Bytes => count_text_files(&files, TokenParser::byte_frequencies)?
+            .map(|frequencies| {
+                file::write_codebook(
+                    &codebook_header,
+                    &builder.build(&frequencies)?.codebook,
+                    &mut codebook,
+                )
+            })
+            .transpose()?,
+    };
+    let Some(codebook_id) = codebook_id else {
+        warn!("No files to build a shared codebook from, so the archive is not solid.");
+        return Ok(None);
+    };
+    info!("Built shared codebook {codebook_id:016x}");
+    Ok(Some(codebook))
+}
+
+/// Count the tokens of each file and merge the counts, skipping files which are not UTF-8 text
+/// rather than failing. Returns `None` if every file was skipped.
+fn count_text_files<T: Hash + Eq>(
+    files: &[&Path],
+    count: impl Fn(Box<dyn BufRead>) -> Result<HashMap<T, u64>, Error>,
+) -> Result<Option<HashMap<T, u64>>, Error> {
+    let mut frequencies = None;
+    for file in files {
+        let reader = BufReader::new(fs::File::open(file)?);
+        match count(Box::new(reader)) {
+            Ok(counts) => {
+                parser::merge_frequencies(frequencies.get_or_insert_with(HashMap::new), counts)?
+            }
+            Err(e) if is_invalid_utf8(&e) => {
+                debug!("{} is not UTF-8 text, leaving it out.", file.display());
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(frequencies)
+}
+
+/// Whether parsing failed because the input is not UTF-8 text, which can still be compressed as
+/// bytes.
+fn is_invalid_utf8(error: &Error) -> bool {
+    matches!(error, Error::Parse(e) if matches!(e.kind, ParseErrorKind::InvalidUtf8))
+}
+
+/// Compress one file of an archive, with the shared codebook of a solid archive if there is one.
+/// A file which turns out not to be UTF-8 text is compressed again as bytes, with a codebook of
+/// its own, unless the token type comes from a codebook file or model.
+pub fn compress_entry(
+    options: &CompressOptions,
+    solid_codebook: Option<&[u8]>,
+    path: &Path,
+) -> Result<Vec<u8>, Error> {
+    let shared = options.codebook.is_some() || options.model.is_some();
+    let mut compressed = Vec::new();
+    let result = match solid_codebook {
+        Some(codebook) => {
+            file::compress_with_codebook(options, codebook, Some(path), &mut compressed)
+        }
+        None => file::compress(options, Some(path), &mut compressed),
+    };
+    match result {
+        Ok(()) => Ok(compressed),
+        Err(e) if !shared && is_invalid_utf8(&e) => {
+            info!(
+                "{} is not UTF-8 text, compressing it as bytes.",
+                path.display()
+            );
+            let options = CompressOptions {
+                token_type: TokenType::Bytes,
+                ..options.clone()
+            };
+            let mut compressed = Vec::new();
+            file::compress(&options, Some(path), &mut compressed)?;
+            Ok(compressed)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The entries with the given names, with everything in the named directories, or all of them if
+/// no names are given. A name may be a pattern, where `*` matches any run of characters other
+/// than `/` and `?` matches any one of them.
+pub fn select_entries(entries: &[Entry], names: &[impl AsRef<str>]) -> Result<Vec<Entry>, Error> {
+    if names.is_empty() {
+        return Ok(entries.to_vec());
+    }
+    let names: Vec<&str> = names
+        .iter()
+        .map(|name| name.as_ref().trim_end_matches('/'))
+        .collect();
+    if let Some(missing) = names.iter().find(|name| {
+        !entries
+            .iter()
+            .any(|entry| glob_matches(name.as_bytes(), entry.path.as_bytes()))
+    }) {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{missing}: not found in the archive"),
+        )));
+    }
+    Ok(entries
+        .iter()
+        .filter(|entry| {
+            // The entry itself, or any directory it is in.
+            let mut paths = entry
+                .path
+                .match_indices('/')
+                .map(|(end, _)| &entry.path[..end])
+                .chain([entry.path.as_str()]);
+            paths.any(|path| {
+                names
+                    .iter()
+                    .any(|name| glob_matches(name.as_bytes(), path.as_bytes()))
+            })
+        })
+        .cloned()
+        .collect())
+}
+
+/// Whether the whole of `path` matches `pattern`, where `*` matches any run of bytes other than
+/// `/`, and `?` any one of them.
+fn glob_matches(pattern: &[u8], path: &[u8]) -> bool {
+    match (pattern.split_first(), path.split_first()) {
+        (None, _) => path.is_empty(),
+        (Some((b'*', rest)), _) => {
+            glob_matches(rest, path)
+                || path
+                    .split_first()
+                    .is_some_and(|(&c, path)| c != b'/' && glob_matches(pattern, path))
+        }
+        (Some((b'?', rest)), Some((&c, path))) => c != b'/' && glob_matches(rest, path),
+        (Some((p, rest)), Some((c, path))) => p == c && glob_matches(rest, path),
+        (Some(_), None) => false,
+    }
+}
+
+/// Decompress the file of an entry, read from `reader`, to `path`, and give it the entry's
+/// permissions and modification time. Unless `force` is set, an existing file is not overwritten.
+/// On failure, nothing is left at `path`.
+pub fn extract_file(
+    entry: &Entry,
+    reader: impl Read,
+    codebook: Option<&[u8]>,
+    limits: &DecodeLimits,
+    path: &Path,
+    force: bool,
+) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut open_options = fs::OpenOptions::new();
+    match force {
+        true => open_options.write(true).create(true).truncate(true),
+        false => open_options.write(true).create_new(true),
+    };
+    let output = open_options.open(path).map_err(|e| match e.kind() {
+        io::ErrorKind::AlreadyExists => io::Error::new(
+            e.kind(),
+            format!(
+                "{} already exists, use --force to overwrite it",
+                path.display()
+            ),
+        ),
+        _ => e,
+    })?;
+
+    let written = (|| -> Result<(), Error> {
+        let mut writer = BufWriter::new(output);
+        let length = file::decompress(reader, codebook, limits, &mut writer)?;
+        huffman::check_length(entry.size, length)?;
+        writer
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(entry.restore_metadata(path)?)
+    })();
+    if let Err(e) = written {
+        // Leave nothing half written behind.
+        fs::remove_file(path)?;
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Permission bits as `ls -l` shows them, e.g. `rw-r--r--`.
+pub fn mode_string(mode: u32) -> String {
+    (0..9)
+        .map(|bit| match mode & (0o400 >> bit) != 0 {
+            true => ['r', 'w', 'x'][bit % 3],
+            false => '-',
+        })
+        .collect()
+}
+
+/// Seconds since the Unix epoch as a UTC date and time, e.g. `2024-01-31 09:05`.
+pub fn utc_date_time(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86_400), secs.rem_euclid(86_400));
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}",
+        secs / 3600,
+        secs % 3600 / 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        application::cli::PayloadType, encoding::huffman::encoder::DEFAULT_MAX_CODE_LENGTH,
+    };
+    use std::io::Cursor;
+
+    fn entry(path: &str, kind: EntryKind) -> Entry {
+        Entry {
+            path: String::from(path),
+            kind,
+            size: 0,
+            mtime: 1_700_000_000,
+            mode: 0o644,
+            offset: 0,
+            length: 0,
+        }
+    }
+
+    fn options(token_type: TokenType) -> CompressOptions {
+        CompressOptions {
+            token_type,
+            payload: PayloadType::Stream,
+            streaming: false,
+            block_size: 1 << 20,
+            max_code_length: DEFAULT_MAX_CODE_LENGTH,
+            codebook: None,
+            model: None,
+        }
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let mut writer = ArchiveWriter::new(Vec::new(), &ArchiveHeader::new()).unwrap();
+        writer
+            .add(entry("docs", EntryKind::Directory), &[])
+            .unwrap();
+        writer
+            .add(entry("docs/a.txt", EntryKind::File), b"aaa")
+            .unwrap();
+        writer.add(entry("b.txt", EntryKind::File), b"bb").unwrap();
+        let archive = writer.finish().unwrap();
+
+        let limits = DecodeLimits::default();
+        let mut reader = ArchiveReader::open(Cursor::new(archive), &limits).unwrap();
+        let entries = reader.entries().to_vec();
+        let paths: Vec<&str> = entries.iter().map(|entry| entry.path.as_str()).collect();
+        assert_eq!(paths, ["docs", "docs/a.txt", "b.txt"]);
+
+        let mut data = Vec::new();
+        reader
+            .open_entry(&entries[2])
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"bb");
+        assert!(reader.shared_codebook().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_solid_archive_holds_its_codebook() {
+        let mut writer = ArchiveWriter::solid(Vec::new(), b"codebook").unwrap();
+        writer.add(entry("a.txt", EntryKind::File), b"aaa").unwrap();
+        let archive = writer.finish().unwrap();
+
+        let limits = DecodeLimits::default();
+        let mut reader = ArchiveReader::open(Cursor::new(archive), &limits).unwrap();
+        let entry = reader.entries()[0].clone();
+        let mut codebook = Vec::new();
+        let mut shared = reader.shared_codebook().unwrap().unwrap();
+        shared.read_to_end(&mut codebook).unwrap();
+        assert_eq!(codebook, b"codebook");
+
+        let mut data = Vec::new();
+        reader
+            .open_entry(&entry)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, b"aaa");
+    }
+
+    #[test]
+    fn test_corrupt_index_offset_is_rejected() {
+        let mut writer = ArchiveWriter::new(Vec::new(), &ArchiveHeader::new()).unwrap();
+        writer.add(entry("a.txt", EntryKind::File), b"aaa").unwrap();
+        let mut archive = writer.finish().unwrap();
+        let trailer = archive.len() - TRAILER_LEN as usize;
+        archive[trailer..].copy_from_slice(&u64::MAX.to_le_bytes());
+
+        let result = ArchiveReader::open(Cursor::new(archive), &DecodeLimits::default());
+        assert!(matches!(result, Err(Error::CorruptData(_))));
+    }
+
+    #[test]
+    fn test_unsafe_paths_are_rejected() {
+        let root = Path::new("out");
+        let path = entry("docs/a.txt", EntryKind::File).path_under(root);
+        assert_eq!(path.unwrap(), root.join("docs").join("a.txt"));
+        for unsafe_path in [
+            "../a.txt",
+            "docs/../../a.txt",
+            "/etc/passwd",
+            "a/./b",
+            "",
+            "a//b",
+        ] {
+            let result = entry(unsafe_path, EntryKind::File).path_under(root);
+            assert!(result.is_err(), "{unsafe_path} was accepted");
+        }
+    }
+
+    #[test]
+    fn test_solid_archive_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let docs = dir.path().join("docs");
+        fs::create_dir(&docs).unwrap();
+        fs::write(docs.join("a.txt"), "the cat sat on the mat\n").unwrap();
+        fs::write(docs.join("b.txt"), "the mat sat on the cat\n").unwrap();
+        fs::write(docs.join("c.bin"), [0xff, 0xfe, 0x00, 0x80]).unwrap();
+
+        let options = options(TokenType::Words);
+        let entries = walk_entries(&[&docs], None).unwrap();
+        let paths: Vec<&str> = entries
+            .iter()
+            .map(|(entry, _)| entry.path.as_str())
+            .collect();
+        assert_eq!(paths, ["docs", "docs/a.txt", "docs/b.txt", "docs/c.bin"]);
+
+        // The binary file is left out of the shared codebook, and compressed on its own.
+        let codebook = solid_codebook(&options, &entries).unwrap().unwrap();
+        let mut writer = ArchiveWriter::solid(Vec::new(), &codebook).unwrap();
+        for (entry, path) in &entries {
+            let compressed = match entry.kind {
+                EntryKind::File => compress_entry(&options, Some(&codebook), path).unwrap(),
+                EntryKind::Directory => Vec::new(),
+            };
+            writer.add(entry.clone(), &compressed).unwrap();
+        }
+        let archive = writer.finish().unwrap();
+
+        let limits = DecodeLimits::default();
+        let mut reader = ArchiveReader::open(Cursor::new(archive), &limits).unwrap();
+        let mut shared = Vec::new();
+        reader
+            .shared_codebook()
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut shared)
+            .unwrap();
+        assert_eq!(shared, codebook);
+
+        let out = dir.path().join("out");
+        for entry in reader.entries().to_vec() {
+            if entry.kind == EntryKind::File {
+                let path = entry.path_under(&out).unwrap();
+                let compressed = reader.open_entry(&entry).unwrap();
+                extract_file(&entry, compressed, Some(&shared), &limits, &path, false).unwrap();
+            }
+        }
+        for name in ["a.txt", "b.txt", "c.bin"] {
+            let extracted = fs::read(out.join("docs").join(name)).unwrap();
+            assert_eq!(extracted, fs::read(docs.join(name)).unwrap(), "{name}");
+        }
+    }
+
+    #[test]
+    fn test_duplicate_entry_names_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        for parent in [&a, &b] {
+            fs::create_dir(parent).unwrap();
+            fs::write(parent.join("notes.txt"), "notes").unwrap();
+        }
+
+        let error = walk_entries(&[a.join("notes.txt"), b.join("notes.txt")], None).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains(&a.join("notes.txt").display().to_string()));
+        assert!(message.contains(&b.join("notes.txt").display().to_string()));
+        assert!(message.ends_with("would both be archived as notes.txt"));
+        assert!(walk_entries(&[&a, &b], None).is_ok());
+    }
+
+    #[test]
+    fn test_extract_file_does_not_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.txt");
+        fs::write(&source, "archived").unwrap();
+        let mut compressed = Vec::new();
+        file::compress(&options(TokenType::Chars), Some(&source), &mut compressed).unwrap();
+        let entry = Entry::from_metadata(String::from("a.txt"), &fs::metadata(&source).unwrap());
+
+        let path = dir.path().join("a.txt");
+        fs::write(&path, "existing").unwrap();
+        let limits = DecodeLimits::default();
+        let result = extract_file(&entry, &compressed[..], None, &limits, &path, false);
+        assert!(matches!(result, Err(Error::Io(e)) if e.kind() == io::ErrorKind::AlreadyExists));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "existing");
+
+        extract_file(&entry, &compressed[..], None, &limits, &path, true).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "archived");
+    }
+
+    #[test]
+    fn test_select_entries() {
+        let entries = [
+            entry("docs", EntryKind::Directory),
+            entry("docs/a.txt", EntryKind::File),
+            entry("docs2", EntryKind::Directory),
+            entry("b.txt", EntryKind::File),
+        ];
+        let select = |names: &[&str]| -> Vec<String> {
+            select_entries(&entries, names)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.path)
+                .collect()
+        };
+        assert_eq!(select(&[]).len(), 4);
+        assert_eq!(select(&["docs/"]), ["docs", "docs/a.txt"]);
+        assert_eq!(select(&["b.txt", "docs/a.txt"]), ["docs/a.txt", "b.txt"]);
+        assert_eq!(select(&["*.txt"]), ["b.txt"]);
+        assert_eq!(select(&["*/*.txt"]), ["docs/a.txt"]);
+        assert_eq!(select(&["docs?"]), ["docs2"]);
+        assert_eq!(select(&["d*"]), ["docs", "docs/a.txt", "docs2"]);
+        assert_eq!(select(&["*"]).len(), 4);
+
+        let missing = select_entries(&entries, &["c.txt"]).unwrap_err();
+        assert_eq!(missing.to_string(), "c.txt: not found in the archive");
+    }
+
+    #[test]
+    fn test_mode_string() {
+        assert_eq!(mode_string(0o644), "rw-r--r--");
+        assert_eq!(mode_string(0o4751), "rwxr-x--x");
+    }
+
+    #[test]
+    fn test_utc_date_time() {
+        assert_eq!(utc_date_time(0), "1970-01-01 00:00");
+        assert_eq!(utc_date_time(-60), "1969-12-31 23:59");
+        assert_eq!(utc_date_time(951_782_400), "2000-02-29 00:00");
+        assert_eq!(utc_date_time(1_709_210_096), "2024-02-29 12:34");
+        assert_eq!(utc_date_time(4_107_542_400), "2100-03-01 00:00");
+    }
+}
@@ -0,0 +1,591 @@
+use crate::{application::cli::TokenType, encoding::huffman::Codebook};
+use serde::Serialize;
+use std::{
+    error::Error,
+    fmt::{self, Display},
+    io::{self, Read, Write},
+};
+
+/// Bytes at the start of every file written by the compressor.
+pub const MAGIC: [u8; 4] = *b"HUF\x1a";
+
+/// Version of the container format written by this build.
+pub const FORMAT_VERSION: u8 = 6;
+
+/// Size of the encoded header, in bytes.
+pub const HEADER_LEN: usize = 16;
+
+/// Set when the body is a sequence of frames written one block at a time, rather than a single
+/// `CompressedData`.
+pub const FLAG_FRAMED: u16 = 1;
+
+/// Set when the input was read from a stream, so the header holds no original length. The length is
+/// recorded at the end of the framed body instead.
+pub const FLAG_UNKNOWN_LENGTH: u16 = 1 << 1;
+
+/// Set when the body holds no codebook, because it was compressed with a shared codebook file. The
+/// ID of the codebook follows the header.
+pub const FLAG_SHARED_CODEBOOK: u16 = 1 << 2;
+
+/// Set when the body holds no codebook, because it was compressed with one of the codebooks built
+/// into the compressor. The one byte ID of the model follows the header.
+pub const FLAG_STATIC_MODEL: u16 = 1 << 3;
+
+/// Flag bits understood by this build. Files with any other bit set are rejected.
+pub const KNOWN_FLAGS: u16 =
+    FLAG_FRAMED | FLAG_UNKNOWN_LENGTH | FLAG_SHARED_CODEBOOK | FLAG_STATIC_MODEL;
+
+/// Suffix added to the name of a file compressed in place, and removed when decompressing it.
+pub const SUFFIX: &str = ".huf";
+
+/// Bytes at the start of every codebook file written by `train`.
+pub const CODEBOOK_MAGIC: [u8; 4] = *b"HUC\x1a";
+
+/// Version of the codebook file format written by this build.
+pub const CODEBOOK_FORMAT_VERSION: u8 = 1;
+
+/// Size of the encoded codebook file header, in bytes.
+pub const CODEBOOK_HEADER_LEN: usize = 8;
+
+/// Bytes at the start of every archive of many files, written by `archive`.
+pub const ARCHIVE_MAGIC: [u8; 4] = *b"HUA\x1a";
+
+/// Version of the archive format written by this build.
+pub const ARCHIVE_FORMAT_VERSION: u8 = 1;
+
+/// Size of the encoded archive header, in bytes.
+pub const ARCHIVE_HEADER_LEN: usize = 8;
+
+/// Suffix of archive file names.
+pub const ARCHIVE_SUFFIX: &str = ".hua";
+
+/// Set when the archive is solid: one codebook, built from the tokens of every file, follows the
+/// archive header in the format of a codebook file, and every file is compressed with it.
+pub const ARCHIVE_FLAG_SOLID: u16 = 1;
+
+/// Flag bits of an archive header understood by this build. Archives with any other bit set are
+/// rejected.
+pub const KNOWN_ARCHIVE_FLAGS: u16 = ARCHIVE_FLAG_SOLID;
+
+/// Fixed size header which precedes the MessagePack body of a compressed file.
+///
+/// Layout (little endian):
+///
+/// | bytes  | field           |
+/// |--------|-----------------|
+/// | 0..4   | magic           |
+/// | 4      | format version  |
+/// | 5      | token type id   |
+/// | 6..8   | flags           |
+/// | 8..16  | original length |
+///
+/// If `FLAG_SHARED_CODEBOOK` is set, the header is followed by the 8 byte ID of the codebook. If
+/// `FLAG_STATIC_MODEL` is set, it is followed by the 1 byte ID of the built-in model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub token_type: TokenType,
+    pub flags: u16,
+    /// Length of the uncompressed input, in bytes.
+    pub original_length: u64,
+    /// ID of the shared codebook the body was compressed with, see `codebook_id`.
+    pub codebook_id: Option<u64>,
+    /// ID of the built-in model the body was compressed with, see `models::Model`.
+    pub model_id: Option<u8>,
+}
+
+impl Header {
+    pub fn new(token_type: TokenType, original_length: u64) -> Self {
+        Header {
+            version: FORMAT_VERSION,
+            token_type,
+            flags: 0,
+            original_length,
+            codebook_id: None,
+            model_id: None,
+        }
+    }
+
+    /// Record that the body was compressed with the shared codebook with this ID.
+    pub fn with_codebook_id(mut self, codebook_id: u64) -> Self {
+        self.flags |= FLAG_SHARED_CODEBOOK;
+        self.codebook_id = Some(codebook_id);
+        self
+    }
+
+    /// Record that the body was compressed with the built-in model with this ID.
+    pub fn with_model_id(mut self, model_id: u8) -> Self {
+        self.flags |= FLAG_STATIC_MODEL;
+        self.model_id = Some(model_id);
+        self
+    }
+
+    /// Size of the header as written, including the IDs which follow it.
+    pub fn encoded_len(&self) -> usize {
+        let mut len = HEADER_LEN;
+        if self.flags & FLAG_SHARED_CODEBOOK != 0 {
+            len += 8;
+        }
+        if self.flags & FLAG_STATIC_MODEL != 0 {
+            len += 1;
+        }
+        len
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut bytes = [0u8; HEADER_LEN];
+        bytes[0..4].copy_from_slice(&MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = token_type_id(self.token_type);
+        bytes[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.original_length.to_le_bytes());
+        writer.write_all(&bytes)?;
+        if self.flags & FLAG_SHARED_CODEBOOK != 0 {
+            writer.write_all(&self.codebook_id.unwrap_or(0).to_le_bytes())?;
+        }
+        if self.flags & FLAG_STATIC_MODEL != 0 {
+            writer.write_all(&[self.model_id.unwrap_or(0)])?;
+        }
+        Ok(())
+    }
+
+    /// Read and validate a header, leaving the reader positioned at the start of the body.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, FormatError> {
+        let mut bytes = [0u8; HEADER_LEN];
+        if let Err(e) = reader.read_exact(&mut bytes) {
+            return Err(match e.kind() {
+                io::ErrorKind::UnexpectedEof => FormatError::NotCompressorFile,
+                _ => FormatError::Io(e),
+            });
+        }
+
+        if bytes[0..4] != MAGIC {
+            return Err(FormatError::NotCompressorFile);
+        }
+
+        let version = bytes[4];
+        if version != FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion {
+                version,
+                supported: FORMAT_VERSION,
+            });
+        }
+
+        let token_type =
+            token_type_from_id(bytes[5]).ok_or(FormatError::UnknownTokenType(bytes[5]))?;
+
+        let flags = u16::from_le_bytes([bytes[6], bytes[7]]);
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(FormatError::UnsupportedFlags(flags));
+        }
+
+        let mut original_length = [0u8; 8];
+        original_length.copy_from_slice(&bytes[8..16]);
+
+        let mut codebook_id = None;
+        if flags & FLAG_SHARED_CODEBOOK != 0 {
+            let mut id = [0u8; 8];
+            reader.read_exact(&mut id).map_err(FormatError::Io)?;
+            codebook_id = Some(u64::from_le_bytes(id));
+        }
+
+        let mut model_id = None;
+        if flags & FLAG_STATIC_MODEL != 0 {
+            let mut id = [0u8; 1];
+            reader.read_exact(&mut id).map_err(FormatError::Io)?;
+            model_id = Some(id[0]);
+        }
+
+        Ok(Header {
+            version,
+            token_type,
+            flags,
+            original_length: u64::from_le_bytes(original_length),
+            codebook_id,
+            model_id,
+        })
+    }
+}
+
+/// Fixed size header which precedes the MessagePack encoded `Codebook` in a codebook file.
+///
+/// Layout:
+///
+/// | bytes  | field          |
+/// |--------|----------------|
+/// | 0..4   | magic          |
+/// | 4      | format version |
+/// | 5      | token type id  |
+/// | 6..8   | reserved       |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodebookHeader {
+    pub version: u8,
+    pub token_type: TokenType,
+}
+
+impl CodebookHeader {
+    pub fn new(token_type: TokenType) -> Self {
+        CodebookHeader {
+            version: CODEBOOK_FORMAT_VERSION,
+            token_type,
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut bytes = [0u8; CODEBOOK_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&CODEBOOK_MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = token_type_id(self.token_type);
+        writer.write_all(&bytes)
+    }
+
+    /// Read and validate a header, leaving the reader positioned at the start of the codebook.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, FormatError> {
+        let mut bytes = [0u8; CODEBOOK_HEADER_LEN];
+        if let Err(e) = reader.read_exact(&mut bytes) {
+            return Err(match e.kind() {
+                io::ErrorKind::UnexpectedEof => FormatError::NotCodebookFile,
+                _ => FormatError::Io(e),
+            });
+        }
+
+        if bytes[0..4] != CODEBOOK_MAGIC {
+            return Err(FormatError::NotCodebookFile);
+        }
+
+        let version = bytes[4];
+        if version != CODEBOOK_FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion {
+                version,
+                supported: CODEBOOK_FORMAT_VERSION,
+            });
+        }
+
+        let token_type =
+            token_type_from_id(bytes[5]).ok_or(FormatError::UnknownTokenType(bytes[5]))?;
+
+        Ok(CodebookHeader {
+            version,
+            token_type,
+        })
+    }
+}
+
+/// Fixed size header at the start of an archive of many files, see `archive`.
+///
+/// Layout (little endian):
+///
+/// | bytes  | field          |
+/// |--------|----------------|
+/// | 0..4   | magic          |
+/// | 4      | format version |
+/// | 5      | reserved       |
+/// | 6..8   | flags          |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveHeader {
+    pub version: u8,
+    pub flags: u16,
+}
+
+impl ArchiveHeader {
+    pub fn new() -> Self {
+        ArchiveHeader {
+            version: ARCHIVE_FORMAT_VERSION,
+            flags: 0,
+        }
+    }
+
+    /// Record that the archive is solid, with one codebook shared by every file.
+    pub fn solid(mut self) -> Self {
+        self.flags |= ARCHIVE_FLAG_SOLID;
+        self
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut bytes = [0u8; ARCHIVE_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&ARCHIVE_MAGIC);
+        bytes[4] = self.version;
+        bytes[6..8].copy_from_slice(&self.flags.to_le_bytes());
+        writer.write_all(&bytes)
+    }
+
+    /// Read and validate a header, leaving the reader positioned after it.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<Self, FormatError> {
+        let mut bytes = [0u8; ARCHIVE_HEADER_LEN];
+        if let Err(e) = reader.read_exact(&mut bytes) {
+            return Err(match e.kind() {
+                io::ErrorKind::UnexpectedEof => FormatError::NotArchiveFile,
+                _ => FormatError::Io(e),
+            });
+        }
+
+        if bytes[0..4] != ARCHIVE_MAGIC {
+            return Err(FormatError::NotArchiveFile);
+        }
+
+        let version = bytes[4];
+        if version != ARCHIVE_FORMAT_VERSION {
+            return Err(FormatError::UnsupportedVersion {
+                version,
+                supported: ARCHIVE_FORMAT_VERSION,
+            });
+        }
+
+        let flags = u16::from_le_bytes([bytes[6], bytes[7]]);
+        if flags & !KNOWN_ARCHIVE_FLAGS != 0 {
+            return Err(FormatError::UnsupportedFlags(flags));
+        }
+
+        Ok(ArchiveHeader { version, flags })
+    }
+}
+
+impl Default for ArchiveHeader {
+    fn default() -> Self {
+        ArchiveHeader::new()
+    }
+}
+
+/// ID which an archive uses to refer to the shared codebook it was compressed with: a hash of the
+/// MessagePack encoding of the codebook.
+pub fn codebook_id<T: Serialize>(codebook: &Codebook<T>) -> u64 {
+    let bytes = rmp_serde::encode::to_vec(codebook).expect("a codebook can always be serialized");
+    xxhash_rust::xxh3::xxh3_64(&bytes)
+}
+
+/// Name of the file a file is compressed to in place, or `None` if it already has the suffix.
+pub fn compressed_path(path: &str) -> Option<String> {
+    match path.ends_with(SUFFIX) {
+        true => None,
+        false => Some(format!("{path}{SUFFIX}")),
+    }
+}
+
+/// Name of the file a compressed file is decompressed to in place, or `None` if it does not have
+/// the suffix.
+pub fn decompressed_path(path: &str) -> Option<String> {
+    path.strip_suffix(SUFFIX)
+        .filter(|stem| !stem.is_empty() && !stem.ends_with('/'))
+        .map(String::from)
+}
+
+fn token_type_id(token_type: TokenType) -> u8 {
+    match token_type {
+        TokenType::Chars => 0,
+        TokenType::Words => 1,
+        TokenType::Bytes => 2,
+    }
+}
+
+fn token_type_from_id(id: u8) -> Option<TokenType> {
+    match id {
+        0 => Some(TokenType::Chars),
+        1 => Some(TokenType::Words),
+        2 => Some(TokenType::Bytes),
+        _ => None,
+    }
+}
+
+/// Reasons a file cannot be read as a compressor container.
+#[derive(Debug)]
+pub enum FormatError {
+    NotCompressorFile,
+    NotCodebookFile,
+    NotArchiveFile,
+    /// The file was written with format `version`, but this build reads only `supported`, the
+    /// version of that kind of file it writes.
+    UnsupportedVersion {
+        version: u8,
+        supported: u8,
+    },
+    UnknownTokenType(u8),
+    UnsupportedFlags(u16),
+    /// The archive was compressed with the shared codebook with this ID, which was not given.
+    MissingCodebook(u64),
+    /// The shared codebook given is not the one the archive was compressed with, which has this ID.
+    WrongCodebook(u64),
+    /// The archive was compressed with a built-in model which this build does not have.
+    UnknownModel(u8),
+    Io(io::Error),
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::NotCompressorFile => write!(f, "not a compressor file"),
+            FormatError::NotCodebookFile => write!(f, "not a codebook file"),
+            FormatError::NotArchiveFile => write!(f, "not an archive of files"),
+            FormatError::UnsupportedVersion { version, supported } => write!(
+                f,
+                "unsupported format version {version} (this build reads version {supported})"
+            ),
+            FormatError::UnknownTokenType(id) => write!(f, "unknown token type id {id}"),
+            FormatError::UnsupportedFlags(flags) => write!(f, "unsupported flags {flags:#06x}"),
+            FormatError::MissingCodebook(id) => write!(
+                f,
+                "compressed with shared codebook {id:016x}, which must be given with --codebook"
+            ),
+            FormatError::WrongCodebook(id) => write!(
+                f,
+                "wrong codebook, the file was compressed with shared codebook {id:016x}"
+            ),
+            FormatError::UnknownModel(id) => write!(f, "unknown built-in model id {id}"),
+            FormatError::Io(e) => write!(f, "failed to read header: {e}"),
+        }
+    }
+}
+
+impl Error for FormatError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FormatError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_round_trip() {
+        let header = Header::new(TokenType::Words, 1552195);
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN);
+        assert_eq!(&bytes[0..4], &MAGIC);
+
+        let read = Header::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read, header);
+    }
+
+    #[test]
+    fn test_foreign_file_is_rejected() {
+        let text = b"Hello world! This is not compressed at all.";
+        assert!(matches!(
+            Header::read_from(&mut &text[..]),
+            Err(FormatError::NotCompressorFile)
+        ));
+        assert!(matches!(
+            Header::read_from(&mut &b"HUF"[..]),
+            Err(FormatError::NotCompressorFile)
+        ));
+    }
+
+    #[test]
+    fn test_unsupported_version_is_rejected() {
+        let mut bytes = Vec::new();
+        Header::new(TokenType::Chars, 0)
+            .write_to(&mut bytes)
+            .unwrap();
+        bytes[4] = FORMAT_VERSION + 1;
+        assert!(matches!(
+            Header::read_from(&mut &bytes[..]),
+            Err(FormatError::UnsupportedVersion { version, .. }) if version == FORMAT_VERSION + 1
+        ));
+
+        // Each kind of file names the version it is read with.
+        let mut bytes = Vec::new();
+        CodebookHeader::new(TokenType::Chars)
+            .write_to(&mut bytes)
+            .unwrap();
+        bytes[4] = 9;
+        let error = CodebookHeader::read_from(&mut &bytes[..]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "unsupported format version 9 (this build reads version {CODEBOOK_FORMAT_VERSION})"
+            )
+        );
+    }
+
+    #[test]
+    fn test_header_with_codebook_id_round_trip() {
+        let header = Header::new(TokenType::Chars, 12).with_codebook_id(0x0123_4567_89ab_cdef);
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 8);
+        assert_eq!(bytes.len(), header.encoded_len());
+
+        let read = Header::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read, header);
+    }
+
+    #[test]
+    fn test_header_with_model_id_round_trip() {
+        let header = Header::new(TokenType::Words, 12).with_model_id(2);
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 1);
+        assert_eq!(bytes.len(), header.encoded_len());
+
+        let read = Header::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read, header);
+    }
+
+    #[test]
+    fn test_suffix_is_added_and_removed() {
+        assert_eq!(
+            compressed_path("notes.txt").as_deref(),
+            Some("notes.txt.huf")
+        );
+        assert_eq!(compressed_path("notes.txt.huf"), None);
+        assert_eq!(
+            decompressed_path("notes.txt.huf").as_deref(),
+            Some("notes.txt")
+        );
+        assert_eq!(decompressed_path("notes.txt"), None);
+        assert_eq!(decompressed_path("dir/.huf"), None);
+    }
+
+    #[test]
+    fn test_archive_header_round_trip() {
+        for header in [ArchiveHeader::new(), ArchiveHeader::new().solid()] {
+            let mut bytes = Vec::new();
+            header.write_to(&mut bytes).unwrap();
+            assert_eq!(bytes.len(), ARCHIVE_HEADER_LEN);
+            assert_eq!(ArchiveHeader::read_from(&mut &bytes[..]).unwrap(), header);
+        }
+
+        // A single compressed file is not an archive.
+        let mut bytes = Vec::new();
+        Header::new(TokenType::Chars, 0)
+            .write_to(&mut bytes)
+            .unwrap();
+        assert!(matches!(
+            ArchiveHeader::read_from(&mut &bytes[..]),
+            Err(FormatError::NotArchiveFile)
+        ));
+    }
+
+    #[test]
+    fn test_codebook_header_round_trip() {
+        let header = CodebookHeader::new(TokenType::Bytes);
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        assert_eq!(CodebookHeader::read_from(&mut &bytes[..]).unwrap(), header);
+
+        // Neither a compressed file nor an archive is a codebook.
+        let mut compressed = Vec::new();
+        Header::new(TokenType::Bytes, 0)
+            .write_to(&mut compressed)
+            .unwrap();
+        let mut archive = Vec::new();
+        ArchiveHeader::new().write_to(&mut archive).unwrap();
+        for bytes in [compressed, archive] {
+            assert!(matches!(
+                CodebookHeader::read_from(&mut &bytes[..]),
+                Err(FormatError::NotCodebookFile)
+            ));
+        }
+    }
+
+    #[test]
+    fn test_codebook_id_depends_on_codes() {
+        let codebook = Codebook::from_code_lengths([('a', 1), ('b', 1)]);
+        assert_eq!(codebook_id(&codebook), codebook_id(&codebook.clone()));
+        let other = Codebook::from_code_lengths([('a', 1), ('c', 1)]);
+        assert_ne!(codebook_id(&codebook), codebook_id(&other));
+    }
+}
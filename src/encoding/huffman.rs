@@ -1,44 +1,1180 @@
+use crate::Error;
 use bit_vec::BitVec;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+pub mod bitstream;
+pub mod checksum;
+pub mod codebook;
+pub mod decoder;
 pub mod encoder;
+pub mod limits;
+pub mod literal;
+pub mod package_merge;
+pub mod summary;
 pub mod tree;
 
-pub use encoder::HuffmanEncoder;
+pub use bitstream::Bitstream;
+pub use codebook::{Codebook, Symbol};
+use crc32fast::Hasher;
+pub use decoder::HuffmanDecoder;
+pub use encoder::{EncoderBuilder, HuffmanEncoder};
+pub use limits::DecodeLimits;
+use limits::LimitedReader;
+pub use literal::Literal;
 use rayon::prelude::*;
-use std::{collections::HashMap, fmt::Display, hash::Hash};
-use tree::HuffmanTree;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    hash::Hash,
+    io::{Read, Write},
+};
 
-/// Compress lines in parallel, using Huffman Coding.
-pub fn compress<'a, T, TExtractor, TokenIterator>(
-    lines: &'a Vec<String>,
-    frequencies: HashMap<T, u32>,
+/// Approximate number of input bytes in each independently decodable block of a bit stream.
+pub const BLOCK_SIZE: usize = 1 << 16;
+
+/// Compress lines into a single bit stream, using Huffman Coding. Blocks of lines are encoded in
+/// parallel.
+pub fn compress<'a, T, L, TExtractor, TokenIterator>(
+    lines: &'a [L],
+    frequencies: HashMap<T, u64>,
+    builder: &EncoderBuilder,
+    get_tokens_from_line: TExtractor,
+) -> Result<CompressedData<T>, Error>
+where
+    T: Hash + Eq + Ord + Clone + Display + Send + Sync + Literal + 'a,
+    L: AsRef<[u8]> + Sync,
+    TExtractor: Fn(&'a L) -> TokenIterator + Send + Sync,
+    TokenIterator: Iterator<Item = T>,
+{
+    let encoder = builder.build(&frequencies)?;
+    Ok(CompressedData {
+        data: encode_stream(lines, &encoder, get_tokens_from_line)?,
+        codebook: Some(encoder.codebook),
+        checksum: checksum::of_lines(lines),
+    })
+}
+
+/// Encode lines into a single bit stream with an existing encoder, such as one rebuilt from a
+/// shared codebook. Each block records the checksum of its lines.
+pub fn encode_stream<'a, T, L, TExtractor, TokenIterator>(
+    lines: &'a [L],
+    encoder: &HuffmanEncoder<T>,
+    get_tokens_from_line: TExtractor,
+) -> Result<Payload, Error>
+where
+    T: Hash + Eq + Ord + Clone + Send + Sync + Literal + 'a,
+    L: AsRef<[u8]> + Sync,
+    TExtractor: Fn(&'a L) -> TokenIterator + Send + Sync,
+    TokenIterator: Iterator<Item = T>,
+{
+    let (blocks, block_checksums) = split_into_blocks(lines, BLOCK_SIZE)
+        .par_iter()
+        .map(|block| {
+            let bits = block.iter().flat_map(&get_tokens_from_line).try_fold(
+                BitVec::new(),
+                |mut bits, token| {
+                    encoder.encode_token(&token, &mut bits)?;
+                    Ok::<_, Error>(bits)
+                },
+            )?;
+            Ok((bits, checksum::of_lines(block)))
+        })
+        .collect::<Result<Vec<_>, Error>>()?
+        .into_iter()
+        .unzip();
+    Ok(Payload::Stream(Bitstream {
+        block_checksums,
+        ..Bitstream::from_blocks(blocks)
+    }))
+}
+
+/// Compress lines in parallel, using Huffman Coding. Each line is stored as a separate bit vector.
+pub fn compress_lines<'a, T, L, TExtractor, TokenIterator>(
+    lines: &'a [L],
+    frequencies: HashMap<T, u64>,
+    builder: &EncoderBuilder,
     get_tokens_from_line: TExtractor,
-) -> CompressedData<T>
+) -> Result<CompressedData<T>, Error>
 where
-    T: Hash + Eq + Clone + Display + Send + Sync + 'a,
-    TExtractor: Fn(&'a str) -> TokenIterator + Send + Sync,
+    T: Hash + Eq + Ord + Clone + Display + Send + Sync + Literal + 'a,
+    L: AsRef<[u8]> + Sync,
+    TExtractor: Fn(&'a L) -> TokenIterator + Send + Sync,
     TokenIterator: Iterator<Item = T>,
 {
-    let huffman_tree = HuffmanTree::from_frequencies(&frequencies);
-    let encoder = HuffmanEncoder::from_huffman_tree(huffman_tree);
-    let decoder = encoder.decoder.clone();
+    let encoder = builder.build(&frequencies)?;
+    Ok(CompressedData {
+        data: encode_lines(lines, &encoder, get_tokens_from_line)?,
+        codebook: Some(encoder.codebook),
+        checksum: checksum::of_lines(lines),
+    })
+}
 
+/// Encode each line as a separate bit vector with an existing encoder, such as one rebuilt from a
+/// shared codebook.
+pub fn encode_lines<'a, T, L, TExtractor, TokenIterator>(
+    lines: &'a [L],
+    encoder: &HuffmanEncoder<T>,
+    get_tokens_from_line: TExtractor,
+) -> Result<Payload, Error>
+where
+    T: Hash + Eq + Ord + Clone + Send + Sync + Literal + 'a,
+    L: AsRef<[u8]> + Sync,
+    TExtractor: Fn(&'a L) -> TokenIterator + Send + Sync,
+    TokenIterator: Iterator<Item = T>,
+{
     let data = lines
         .par_iter()
         .map(|line| {
-            get_tokens_from_line(line)
-                .map(|token| encoder.encoder.get(&token).unwrap().clone())
-                .fold(BitVec::new(), |mut vec1, vec2| {
-                    vec1.extend(vec2);
-                    vec1
-                })
+            get_tokens_from_line(line).try_fold(BitVec::new(), |mut bits, token| {
+                encoder.encode_token(&token, &mut bits)?;
+                Ok(bits)
+            })
         })
-        .collect();
-    CompressedData { decoder, data }
+        .collect::<Result<_, Error>>()?;
+    Ok(Payload::Lines(data))
+}
+
+/// Deserialize a `CompressedData` body, failing if it is larger than `limits.max_body_size`.
+pub fn read_compressed<T, R>(reader: R, limits: &DecodeLimits) -> Result<CompressedData<T>, Error>
+where
+    T: DeserializeOwned,
+    R: Read,
+{
+    let mut deserializer =
+        rmp_serde::Deserializer::new(LimitedReader::new(reader, limits.max_body_size));
+    let compressed = deserialize_limited(&mut deserializer, limits)?;
+    check_end(deserializer.get_mut())?;
+    Ok(compressed)
+}
+
+/// Decode compressed data, whichever payload it was stored with.
+///
+/// `shared_codebook` is used if the data was compressed without a codebook of its own. The
+/// codebook and payload are validated before decoding, so corrupt data fails rather than panics.
+/// Unless `limits.verify_checksums` is turned off, the text of each block and the whole text are
+/// checked against their checksums, failing with `Error::ChecksumMismatch`.
+pub fn decompress<T>(
+    compressed: CompressedData<T>,
+    shared_codebook: Option<Codebook<T>>,
+    limits: &DecodeLimits,
+    tokens_to_text: impl Fn(Vec<T>) -> Vec<u8> + Send + Sync,
+) -> Result<Vec<u8>, Error>
+where
+    T: Ord + Clone + Send + Sync + Literal,
+{
+    let codebook = compressed
+        .codebook
+        .or(shared_codebook)
+        .ok_or_else(missing_codebook)?;
+    codebook.validate(limits)?;
+    compressed.data.validate()?;
+    let decoder = HuffmanDecoder::from_codebook(codebook);
+    let text = match compressed.data {
+        Payload::Lines(lines) => decoder.decode(&lines, tokens_to_text)?,
+        Payload::Stream(mut stream) => {
+            if !limits.verify_checksums {
+                stream.block_checksums.clear();
+            }
+            decoder.decode_stream(&stream, tokens_to_text)?
+        }
+    };
+    if limits.verify_checksums {
+        checksum::verify(compressed.checksum, &text)?;
+    }
+    Ok(text)
+}
+
+/// Compress an input one block at a time, writing each block to `writer` as a frame as soon as it
+/// has been encoded.
+///
+/// The frequencies must have been counted over the same input in an earlier pass. Only the
+/// codebook and the current block are held in memory.
+pub fn compress_framed<T, L, E, W>(
+    lines: impl Iterator<Item = Result<L, E>>,
+    frequencies: HashMap<T, u64>,
+    builder: &EncoderBuilder,
+    writer: &mut W,
+    get_tokens_from_line: impl Fn(&L) -> Vec<T>,
+) -> Result<(), Error>
+where
+    T: Hash + Eq + Ord + Clone + Send + Sync + Serialize + Literal,
+    L: AsRef<[u8]>,
+    E: Into<Error>,
+    W: Write,
+{
+    let encoder = builder.build(&frequencies)?;
+    encode_framed(lines, &encoder, true, writer, get_tokens_from_line)
+}
+
+/// Encode an input one block at a time with an existing encoder, writing each block to `writer` as
+/// a frame as soon as it has been encoded.
+///
+/// The codebook is written with the first block if `embed_codebook` is set. Otherwise the body can
+/// only be decoded with the same codebook, e.g. a shared codebook file.
+pub fn encode_framed<T, L, E, W>(
+    lines: impl Iterator<Item = Result<L, E>>,
+    encoder: &HuffmanEncoder<T>,
+    embed_codebook: bool,
+    writer: &mut W,
+    get_tokens_from_line: impl Fn(&L) -> Vec<T>,
+) -> Result<(), Error>
+where
+    T: Hash + Eq + Ord + Clone + Send + Sync + Serialize + Literal,
+    L: AsRef<[u8]>,
+    E: Into<Error>,
+    W: Write,
+{
+    // Every block shares the same codebook, so it is only written with the first one.
+    let mut codebook = embed_codebook.then(|| encoder.codebook.clone());
+    let mut bits = BitVec::new();
+    let mut block_bytes = 0;
+    let mut block_checksum = Hasher::new();
+    let mut original_length = 0;
+    let mut checksum = Hasher::new();
+    for line in lines {
+        let line = line.map_err(Into::into)?;
+        for token in get_tokens_from_line(&line) {
+            encoder.encode_token(&token, &mut bits)?;
+        }
+        block_bytes += line.as_ref().len();
+        block_checksum.update(line.as_ref());
+        original_length += line.as_ref().len() as u64;
+        checksum.update(line.as_ref());
+
+        if block_bytes >= BLOCK_SIZE {
+            write_frame(
+                writer,
+                &Frame::Block {
+                    codebook: codebook.take(),
+                    bits: std::mem::take(&mut bits),
+                    checksum: std::mem::take(&mut block_checksum).finalize(),
+                },
+            )?;
+            block_bytes = 0;
+        }
+    }
+    if block_bytes > 0 {
+        write_frame(
+            writer,
+            &Frame::Block {
+                codebook: codebook.take(),
+                bits,
+                checksum: block_checksum.finalize(),
+            },
+        )?;
+    }
+    write_frame(
+        writer,
+        &Frame::<T>::End {
+            original_length,
+            checksum: checksum.finalize(),
+        },
+    )?;
+    Ok(writer.flush()?)
+}
+
+/// Compress an input which can only be read once, such as stdin, in independent blocks of about
+/// `block_size` bytes.
+///
+/// Each block has its own Huffman tree and codebook, and is written to `writer` as a frame as soon
+/// as it fills, so memory use does not grow with the length of the input.
+pub fn compress_blocks<T, L, E, W>(
+    lines: impl Iterator<Item = Result<L, E>>,
+    writer: &mut W,
+    block_size: usize,
+    builder: &EncoderBuilder,
+    get_tokens_from_line: impl Fn(&L) -> Vec<T>,
+) -> Result<(), Error>
+where
+    T: Hash + Eq + Ord + Clone + Send + Sync + Serialize + Literal,
+    L: AsRef<[u8]>,
+    E: Into<Error>,
+    W: Write,
+{
+    let mut tokens = Vec::new();
+    let mut frequencies = HashMap::new();
+    let mut block_bytes = 0;
+    let mut block_checksum = Hasher::new();
+    let mut original_length = 0;
+    let mut checksum = Hasher::new();
+    for line in lines {
+        let line = line.map_err(Into::into)?;
+        for token in get_tokens_from_line(&line) {
+            *frequencies.entry(token.clone()).or_insert(0) += 1;
+            tokens.push(token);
+        }
+        block_bytes += line.as_ref().len();
+        block_checksum.update(line.as_ref());
+        original_length += line.as_ref().len() as u64;
+        checksum.update(line.as_ref());
+
+        if block_bytes >= block_size {
+            let frequencies = std::mem::take(&mut frequencies);
+            let block_checksum = std::mem::take(&mut block_checksum).finalize();
+            let encoder = builder.build(&frequencies)?;
+            write_block(writer, encoder, tokens.drain(..), block_checksum)?;
+            block_bytes = 0;
+        }
+    }
+    if block_bytes > 0 {
+        let encoder = builder.build(&frequencies)?;
+        write_block(writer, encoder, tokens.drain(..), block_checksum.finalize())?;
+    }
+    write_frame(
+        writer,
+        &Frame::<T>::End {
+            original_length,
+            checksum: checksum.finalize(),
+        },
+    )?;
+    Ok(writer.flush()?)
+}
+
+/// Encode a block with a codebook of its own, and write it as a frame.
+fn write_block<T, W>(
+    writer: &mut W,
+    encoder: HuffmanEncoder<T>,
+    tokens: impl Iterator<Item = T>,
+    checksum: u32,
+) -> Result<(), Error>
+where
+    T: Hash + Eq + Ord + Clone + Send + Sync + Serialize + Literal,
+    W: Write,
+{
+    let mut bits = BitVec::new();
+    for token in tokens {
+        encoder.encode_token(&token, &mut bits)?;
+    }
+    write_frame(
+        writer,
+        &Frame::Block {
+            codebook: Some(encoder.codebook),
+            bits,
+            checksum,
+        },
+    )?;
+    Ok(writer.flush()?)
+}
+
+/// Decode a framed body, writing the text of each block to `writer` as soon as it is decoded.
+///
+/// Blocks before the first one with a codebook of its own are decoded with `shared_codebook`.
+/// Each frame is limited to `limits.max_body_size` bytes, and each codebook is validated before
+/// decoding with it.
+///
+/// Returns the number of bytes decoded, after checking it against the length recorded at the end
+/// of the body. A corrupt block fails with an error naming it. Unless `limits.verify_checksums` is
+/// turned off, each block is checked against its checksum before it is written, and the whole text
+/// is checked at the end.
+pub fn decompress_framed<T, R, W>(
+    reader: R,
+    writer: &mut W,
+    shared_codebook: Option<Codebook<T>>,
+    limits: &DecodeLimits,
+    tokens_to_text: impl Fn(Vec<T>) -> Vec<u8>,
+) -> Result<u64, Error>
+where
+    T: Ord + Clone + Send + Sync + DeserializeOwned + Literal,
+    R: Read,
+    W: Write,
+{
+    let mut deserializer =
+        rmp_serde::Deserializer::new(LimitedReader::new(reader, limits.max_body_size));
+    let mut decoder = match shared_codebook {
+        Some(codebook) => {
+            codebook.validate(limits)?;
+            Some(HuffmanDecoder::from_codebook(codebook))
+        }
+        None => None,
+    };
+    let mut decoded_length = 0;
+    let mut decoded_checksum = Hasher::new();
+    for block in 0.. {
+        let frame: Frame<T> = deserialize_limited(&mut deserializer, limits)?;
+        match frame {
+            Frame::Block {
+                codebook,
+                bits,
+                checksum,
+            } => {
+                let in_block = |e: Error| e.in_part(format!("block {block}"));
+                if let Some(codebook) = codebook {
+                    codebook.validate(limits).map_err(in_block)?;
+                    decoder = Some(HuffmanDecoder::from_codebook(codebook));
+                }
+                let decoder = decoder.as_ref().ok_or_else(missing_codebook)?;
+                let text = tokens_to_text(decoder.decode_bits(&bits).map_err(in_block)?);
+                if limits.verify_checksums {
+                    checksum::verify(checksum, &text).map_err(in_block)?;
+                    decoded_checksum.update(&text);
+                }
+                writer.write_all(&text)?;
+                writer.flush()?;
+                decoded_length += text.len() as u64;
+            }
+            Frame::End {
+                original_length,
+                checksum,
+            } => {
+                check_end(deserializer.get_mut())?;
+                check_length(original_length, decoded_length)?;
+                if limits.verify_checksums {
+                    checksum::check(checksum, decoded_checksum.finalize())?;
+                }
+                return Ok(decoded_length);
+            }
+        }
+    }
+    unreachable!("the body ends with an End frame or an error")
+}
+
+/// Check that decoding gave back as many bytes as were compressed.
+pub fn check_length(original_length: u64, decoded_length: u64) -> Result<(), Error> {
+    if original_length != decoded_length {
+        return Err(Error::CorruptData(format!(
+            "decoded {decoded_length} bytes, but {original_length} were compressed"
+        )));
+    }
+    Ok(())
+}
+
+fn missing_codebook() -> Error {
+    Error::InvalidFormat(String::from(
+        "compressed data has no codebook, and no shared codebook was given",
+    ))
+}
+
+/// Deserialize the next value, allowing it up to `limits.max_body_size` bytes.
+fn deserialize_limited<D, R>(
+    deserializer: &mut rmp_serde::Deserializer<rmp_serde::decode::ReadReader<LimitedReader<R>>>,
+    limits: &DecodeLimits,
+) -> Result<D, Error>
+where
+    D: DeserializeOwned,
+    R: Read,
+{
+    deserializer.get_mut().reset();
+    D::deserialize(&mut *deserializer).map_err(|e| {
+        if deserializer.get_ref().exceeded() {
+            Error::InvalidFormat(format!(
+                "body is larger than the limit of {} bytes",
+                limits.max_body_size
+            ))
+        } else {
+            e.into()
+        }
+    })
+}
+
+/// Check that nothing follows the end of the body.
+fn check_end<R: Read>(reader: &mut LimitedReader<R>) -> Result<(), Error> {
+    reader.reset();
+    match reader.read(&mut [0])? {
+        0 => Ok(()),
+        _ => Err(Error::CorruptData(String::from(
+            "unexpected data after the end of the body",
+        ))),
+    }
+}
+
+fn write_frame<T: Serialize, W: Write>(writer: &mut W, frame: &Frame<T>) -> Result<(), Error> {
+    Ok(rmp_serde::encode::write(writer, frame)?)
+}
+
+/// Group consecutive lines into blocks of at least `block_size` bytes, apart from the last block.
+fn split_into_blocks<L: AsRef<[u8]>>(lines: &[L], block_size: usize) -> Vec<&[L]> {
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    let mut bytes = 0;
+    for (i, line) in lines.iter().enumerate() {
+        bytes += line.as_ref().len();
+        if bytes >= block_size {
+            blocks.push(&lines[start..=i]);
+            start = i + 1;
+            bytes = 0;
+        }
+    }
+    if start < lines.len() {
+        blocks.push(&lines[start..]);
+    }
+    blocks
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct CompressedData<T> {
-    pub data: Vec<BitVec>,
-    pub decoder: HashMap<BitVec, T>,
+    pub data: Payload,
+    /// Code lengths from which the decoder rebuilds the canonical codes. Omitted when compressed
+    /// with a shared codebook.
+    pub codebook: Option<Codebook<T>>,
+    /// CRC32 of the original input, see `checksum::of_lines`.
+    pub checksum: u32,
+}
+
+/// Layout of the encoded text.
+#[derive(Serialize, Deserialize)]
+pub enum Payload {
+    /// One bit vector per line.
+    Lines(Vec<BitVec>),
+    /// All lines in one bit stream, with a block index for parallel decoding.
+    Stream(Bitstream),
+}
+
+impl Payload {
+    /// Check that the bits read from untrusted input are consistent, so decoding cannot read past
+    /// them.
+    pub fn validate(&self) -> Result<(), Error> {
+        match self {
+            Payload::Lines(lines) => {
+                for (line, bits) in lines.iter().enumerate() {
+                    // The length is stored separately from the bits, so may claim more than exist.
+                    if bits.storage().len() * 32 < bits.len() {
+                        return Err(Error::CorruptData(format!(
+                            "line {line} claims {} bits but holds {}",
+                            bits.len(),
+                            bits.storage().len() * 32
+                        )));
+                    }
+                }
+                Ok(())
+            }
+            Payload::Stream(stream) => stream.validate(),
+        }
+    }
+}
+
+/// Unit of a framed body. A framed body is a sequence of blocks followed by `End`.
+#[derive(Serialize, Deserialize)]
+pub enum Frame<T> {
+    Block {
+        /// Codebook for this and the following blocks. Omitted when unchanged from the previous
+        /// block.
+        codebook: Option<Codebook<T>>,
+        #[serde(with = "bitstream::packed_bits")]
+        bits: BitVec,
+        /// CRC32 of the original bytes of this block.
+        checksum: u32,
+    },
+    End {
+        /// Length of the uncompressed input, which is not known up front when reading from a
+        /// stream.
+        original_length: u64,
+        /// CRC32 of the whole uncompressed input.
+        checksum: u32,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::application::parser::{read_chunks, read_lines, TokenParser};
+    use std::{cell::RefCell, io, rc::Rc};
+
+    #[test]
+    fn test_split_into_blocks() {
+        let lines: Vec<String> = ["abc\n", "de\n", "f\n", "ghij\n", "k"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let blocks = split_into_blocks(&lines, 6);
+        assert_eq!(
+            blocks,
+            vec![&lines[0..2], &lines[2..4], &lines[4..5]] as Vec<&[String]>
+        );
+    }
+
+    #[test]
+    fn test_stream_and_lines_payloads_round_trip() {
+        let text = "It was the best of times,\nit was the worst of times,\n".repeat(3000);
+        let input = TokenParser::chars_from_reader(text.as_bytes()).unwrap();
+
+        let stream = compress(
+            &input.lines,
+            input.token_frequencies.clone(),
+            &EncoderBuilder::new(),
+            |line| line.chars(),
+        )
+        .unwrap();
+        match &stream.data {
+            Payload::Stream(bitstream) => assert!(bitstream.block_ends.len() > 1),
+            Payload::Lines(_) => panic!("expected a stream payload"),
+        }
+        let lines = compress_lines(
+            &input.lines,
+            input.token_frequencies,
+            &EncoderBuilder::new(),
+            |line| line.chars(),
+        )
+        .unwrap();
+
+        for compressed in [stream, lines] {
+            let decompressed = decompress(
+                compressed,
+                None,
+                &DecodeLimits::default(),
+                |tokens: Vec<char>| tokens.into_iter().collect::<String>().into_bytes(),
+            )
+            .unwrap();
+            assert_eq!(decompressed, text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_framed_round_trip() {
+        let text = "It was the best of times,\nit was the worst of times,\n".repeat(3000);
+        let frequencies = TokenParser::word_frequencies(text.as_bytes()).unwrap();
+
+        let mut framed = Vec::new();
+        compress_framed(
+            read_lines(text.as_bytes()),
+            frequencies,
+            &EncoderBuilder::new(),
+            &mut framed,
+            |line| line.split_inclusive(' ').map(String::from).collect(),
+        )
+        .unwrap();
+
+        let mut decompressed = Vec::new();
+        decompress_framed(
+            &framed[..],
+            &mut decompressed,
+            None,
+            &DecodeLimits::default(),
+            |tokens: Vec<String>| tokens.join("").into_bytes(),
+        )
+        .unwrap();
+        assert_eq!(decompressed, text.as_bytes());
+    }
+
+    #[test]
+    fn test_truncated_frames_are_rejected() {
+        let text = "It was the best of times,\nit was the worst of times,\n";
+        let frequencies = TokenParser::char_frequencies(text.as_bytes()).unwrap();
+
+        let mut framed = Vec::new();
+        compress_framed(
+            read_lines(text.as_bytes()),
+            frequencies,
+            &EncoderBuilder::new(),
+            &mut framed,
+            |line| line.chars().collect(),
+        )
+        .unwrap();
+        framed.pop();
+
+        let result = decompress_framed(
+            &framed[..],
+            &mut std::io::sink(),
+            None,
+            &DecodeLimits::default(),
+            |tokens: Vec<char>| tokens.into_iter().collect::<String>().into_bytes(),
+        );
+        assert!(matches!(result, Err(Error::InvalidFormat(_))));
+    }
+
+    #[test]
+    fn test_independent_blocks_round_trip() {
+        let text = "It was the best of times,\nit was the worst of times,\n".repeat(100)
+            + "Call me Ishmael.\n";
+
+        let mut framed = Vec::new();
+        compress_blocks(
+            read_lines(text.as_bytes()),
+            &mut framed,
+            1000,
+            &EncoderBuilder::new(),
+            |line| line.chars().collect(),
+        )
+        .unwrap();
+
+        let mut deserializer = rmp_serde::Deserializer::new(&framed[..]);
+        let mut blocks = 0;
+        while let Frame::Block { codebook, .. } =
+            Frame::<char>::deserialize(&mut deserializer).unwrap()
+        {
+            assert!(codebook.is_some());
+            blocks += 1;
+        }
+        assert_eq!(blocks, 6);
+
+        let mut decompressed = Vec::new();
+        decompress_framed(
+            &framed[..],
+            &mut decompressed,
+            None,
+            &DecodeLimits::default(),
+            |tokens: Vec<char>| tokens.into_iter().collect::<String>().into_bytes(),
+        )
+        .unwrap();
+        assert_eq!(decompressed, text.as_bytes());
+    }
+
+    #[test]
+    fn test_blocks_are_written_before_the_end_of_the_input() {
+        /// Writer whose output only becomes visible once flushed, like a `BufWriter` on stdout.
+        struct Flushed {
+            pending: Vec<u8>,
+            visible: Rc<RefCell<Vec<u8>>>,
+        }
+        impl Write for Flushed {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.pending.extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.visible.borrow_mut().append(&mut self.pending);
+                Ok(())
+            }
+        }
+
+        let visible = Rc::new(RefCell::new(Vec::new()));
+        let mut writer = Flushed {
+            pending: Vec::new(),
+            visible: Rc::clone(&visible),
+        };
+        // Record what had been written out by the time each line was read.
+        let mut seen = Vec::new();
+        let lines = [
+            "It was the best of times,\n",
+            "it was the worst of times,\n",
+        ]
+        .into_iter()
+        .map(|line| {
+            seen.push(visible.borrow().len());
+            Ok::<_, Error>(String::from(line))
+        });
+        compress_blocks(lines, &mut writer, 10, &EncoderBuilder::new(), |line| {
+            line.chars().collect()
+        })
+        .unwrap();
+
+        assert_eq!(seen[0], 0);
+        // The first line filled a block, which was written before the second line was read.
+        assert!(seen[1] > 0);
+        assert!(seen[1] < visible.borrow().len());
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let bytes: Vec<u8> = (0..=255u8)
+            .cycle()
+            .take(5000)
+            .chain(*b"\xff\xfe\n")
+            .collect();
+
+        let input = TokenParser::bytes_from_reader(&bytes[..]).unwrap();
+        let compressed = compress(
+            &input.lines,
+            input.token_frequencies,
+            &EncoderBuilder::new(),
+            |chunk| chunk.iter().copied(),
+        )
+        .unwrap();
+        assert_eq!(
+            decompress(
+                compressed,
+                None,
+                &DecodeLimits::default(),
+                |tokens: Vec<u8>| tokens
+            )
+            .unwrap(),
+            bytes
+        );
+
+        let mut framed = Vec::new();
+        compress_blocks(
+            read_chunks(&bytes[..]),
+            &mut framed,
+            1000,
+            &EncoderBuilder::new(),
+            |chunk| chunk.clone(),
+        )
+        .unwrap();
+        let mut decompressed = Vec::new();
+        decompress_framed(
+            &framed[..],
+            &mut decompressed,
+            None,
+            &DecodeLimits::default(),
+            |tokens: Vec<u8>| tokens,
+        )
+        .unwrap();
+        assert_eq!(decompressed, bytes);
+    }
+
+    #[test]
+    fn test_empty_and_single_token_inputs_round_trip() {
+        for text in ["", "aaaa", "\n\n\n"] {
+            let input = TokenParser::chars_from_reader(text.as_bytes()).unwrap();
+            let compressed = compress(
+                &input.lines,
+                input.token_frequencies,
+                &EncoderBuilder::new(),
+                |line| line.chars(),
+            )
+            .unwrap();
+            let decompressed = decompress(
+                compressed,
+                None,
+                &DecodeLimits::default(),
+                |tokens: Vec<char>| tokens.into_iter().collect::<String>().into_bytes(),
+            )
+            .unwrap();
+            assert_eq!(decompressed, text.as_bytes());
+
+            let frequencies = TokenParser::word_frequencies(text.as_bytes()).unwrap();
+            let mut framed = Vec::new();
+            compress_framed(
+                read_lines(text.as_bytes()),
+                frequencies,
+                &EncoderBuilder::new(),
+                &mut framed,
+                |line| line.split_inclusive(' ').map(String::from).collect(),
+            )
+            .unwrap();
+            let mut decompressed = Vec::new();
+            decompress_framed(
+                &framed[..],
+                &mut decompressed,
+                None,
+                &DecodeLimits::default(),
+                |tokens: Vec<String>| tokens.join("").into_bytes(),
+            )
+            .unwrap();
+            assert_eq!(decompressed, text.as_bytes());
+
+            let mut framed = Vec::new();
+            compress_blocks(
+                read_chunks(text.as_bytes()),
+                &mut framed,
+                2,
+                &EncoderBuilder::new(),
+                |chunk| chunk.clone(),
+            )
+            .unwrap();
+            let mut decompressed = Vec::new();
+            decompress_framed(
+                &framed[..],
+                &mut decompressed,
+                None,
+                &DecodeLimits::default(),
+                |tokens: Vec<u8>| tokens,
+            )
+            .unwrap();
+            assert_eq!(decompressed, text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_shared_codebook_round_trip() {
+        let corpus = "the cat sat on the mat\n".repeat(10);
+        let frequencies = TokenParser::word_frequencies(corpus.as_bytes()).unwrap();
+        let encoder = EncoderBuilder::new().build(&frequencies).unwrap();
+
+        // Words missing from the codebook are escaped.
+        let text = "the dog sat on the cat\n";
+        let input = TokenParser::words_from_reader(text.as_bytes()).unwrap();
+        let compressed = CompressedData {
+            data: encode_stream(&input.lines, &encoder, |line| {
+                line.split_inclusive(' ').map(String::from)
+            })
+            .unwrap(),
+            codebook: None,
+            checksum: checksum::of_lines(&input.lines),
+        };
+        let to_text = |tokens: Vec<String>| tokens.join("").into_bytes();
+        let decompressed = decompress(
+            compressed,
+            Some(encoder.codebook.clone()),
+            &DecodeLimits::default(),
+            to_text,
+        );
+        assert_eq!(decompressed.unwrap(), text.as_bytes());
+
+        let mut framed = Vec::new();
+        encode_framed(
+            read_lines(text.as_bytes()),
+            &encoder,
+            false,
+            &mut framed,
+            |line| line.split_inclusive(' ').map(String::from).collect(),
+        )
+        .unwrap();
+        let result = decompress_framed(
+            &framed[..],
+            &mut std::io::sink(),
+            None,
+            &DecodeLimits::default(),
+            to_text,
+        );
+        assert!(result.is_err());
+
+        let mut decompressed = Vec::new();
+        let shared = Some(encoder.codebook);
+        decompress_framed(
+            &framed[..],
+            &mut decompressed,
+            shared,
+            &DecodeLimits::default(),
+            to_text,
+        )
+        .unwrap();
+        assert_eq!(decompressed, text.as_bytes());
+    }
+
+    #[test]
+    fn test_malformed_codebook_is_corrupt_data() {
+        let text = "abracadabra\n";
+        let input = TokenParser::chars_from_reader(text.as_bytes()).unwrap();
+        let to_text = |tokens: Vec<char>| tokens.into_iter().collect::<String>().into_bytes();
+        let compress = || {
+            compress_chars(&input.lines, input.token_frequencies.clone(), |line| {
+                line.chars()
+            })
+        };
+
+        // Codes which are not prefix-free.
+        let mut compressed = compress();
+        let codebook = compressed.codebook.as_mut().unwrap();
+        codebook.lengths.iter_mut().for_each(|length| *length = 1);
+        let result = decompress(compressed, None, &DecodeLimits::default(), to_text);
+        assert!(matches!(result, Err(Error::CorruptData(_))));
+
+        // An empty code, which would decode to endless tokens without reading any bits.
+        let mut compressed = compress();
+        compressed.codebook.as_mut().unwrap().lengths[0] = 0;
+        let result = decompress(compressed, None, &DecodeLimits::default(), to_text);
+        assert!(matches!(result, Err(Error::CorruptData(_))));
+
+        let limits = DecodeLimits {
+            max_symbols: 3,
+            ..DecodeLimits::default()
+        };
+        let result = decompress(compress(), None, &limits, to_text);
+        assert!(matches!(result, Err(Error::CorruptData(_))));
+    }
+
+    #[test]
+    fn test_code_of_the_longest_length_is_decoded() {
+        // A crafted body whose codebook holds one token with a code of 64 bits, the longest a
+        // codebook may hold.
+        let compressed = CompressedData {
+            data: Payload::Lines(vec![BitVec::from_elem(64, false)]),
+            codebook: Some(Codebook {
+                symbols: vec!['a'],
+                lengths: vec![64],
+                escape: None,
+            }),
+            checksum: crc32fast::hash(b"a"),
+        };
+        let body = rmp_serde::encode::to_vec(&compressed).unwrap();
+
+        let limits = DecodeLimits::default();
+        let compressed: CompressedData<char> = read_compressed(&body[..], &limits).unwrap();
+        let decompressed = decompress(compressed, None, &limits, |tokens: Vec<char>| {
+            tokens.into_iter().collect::<String>().into_bytes()
+        });
+        assert_eq!(decompressed.unwrap(), b"a");
+    }
+
+    fn compress_chars<'a>(
+        lines: &'a [String],
+        frequencies: HashMap<char, u64>,
+        get_tokens: impl Fn(&'a String) -> std::str::Chars<'a> + Send + Sync,
+    ) -> CompressedData<char> {
+        compress(lines, frequencies, &EncoderBuilder::new(), get_tokens).unwrap()
+    }
+
+    #[test]
+    fn test_bits_past_their_storage_are_corrupt_data() {
+        // Same layout as a `BitVec`, but claiming more bits than it stores.
+        #[derive(Serialize)]
+        struct ClaimedBits {
+            storage: Vec<u32>,
+            nbits: usize,
+        }
+        let bytes = rmp_serde::encode::to_vec(&ClaimedBits {
+            storage: vec![0],
+            nbits: 1000,
+        })
+        .unwrap();
+        let compressed = CompressedData {
+            data: Payload::Lines(vec![rmp_serde::decode::from_slice(&bytes).unwrap()]),
+            codebook: Some(Codebook::from_code_lengths([(b'a', 1), (b'b', 1)])),
+            checksum: 0,
+        };
+        let result = decompress(compressed, None, &DecodeLimits::default(), |tokens| tokens);
+        assert!(matches!(result, Err(Error::CorruptData(_))));
+    }
+
+    #[test]
+    fn test_body_larger_than_limit_is_rejected() {
+        let text = "It was the best of times,\nit was the worst of times,\n".repeat(100);
+        let input = TokenParser::chars_from_reader(text.as_bytes()).unwrap();
+        let compressed = compress_chars(&input.lines, input.token_frequencies, |line| line.chars());
+        let body = rmp_serde::encode::to_vec(&compressed).unwrap();
+
+        let limits = DecodeLimits {
+            max_body_size: body.len() as u64,
+            ..DecodeLimits::default()
+        };
+        assert!(read_compressed::<char, _>(&body[..], &limits).is_ok());
+        let trailing = [&body[..], b"x"].concat();
+        assert!(matches!(
+            read_compressed::<char, _>(&trailing[..], &limits),
+            Err(Error::CorruptData(_))
+        ));
+        let limits = DecodeLimits {
+            max_body_size: body.len() as u64 - 1,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            read_compressed::<char, _>(&body[..], &limits),
+            Err(Error::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_checksum_mismatch_is_detected() {
+        let text = "It was the best of times,\nit was the worst of times,\n".repeat(3000);
+        let input = TokenParser::chars_from_reader(text.as_bytes()).unwrap();
+        let to_text = |tokens: Vec<char>| tokens.into_iter().collect::<String>().into_bytes();
+        let compress = || {
+            compress_chars(&input.lines, input.token_frequencies.clone(), |line| {
+                line.chars()
+            })
+        };
+        let unverified = DecodeLimits {
+            verify_checksums: false,
+            ..DecodeLimits::default()
+        };
+
+        let mut compressed = compress();
+        compressed.checksum ^= 1;
+        let result = decompress(compressed, None, &DecodeLimits::default(), to_text);
+        assert!(matches!(
+            result,
+            Err(Error::ChecksumMismatch { part: None, .. })
+        ));
+
+        let mut compressed = compress();
+        let Payload::Stream(stream) = &mut compressed.data else {
+            panic!("expected a stream payload");
+        };
+        stream.block_checksums[1] ^= 1;
+        let result = decompress(compressed, None, &DecodeLimits::default(), to_text);
+        assert!(matches!(
+            result,
+            Err(Error::ChecksumMismatch { part: Some(part), .. }) if part == "block 1"
+        ));
+
+        let mut compressed = compress();
+        compressed.checksum ^= 1;
+        assert!(decompress(compressed, None, &unverified, to_text).is_ok());
+
+        let mut framed = Vec::new();
+        compress_framed(
+            read_lines(text.as_bytes()),
+            input.token_frequencies.clone(),
+            &EncoderBuilder::new(),
+            &mut framed,
+            |line| line.chars().collect(),
+        )
+        .unwrap();
+        // The checksum of the whole text is the last field of the body.
+        *framed.last_mut().unwrap() ^= 1;
+        for (limits, ok) in [(DecodeLimits::default(), false), (unverified, true)] {
+            let result =
+                decompress_framed(&framed[..], &mut std::io::sink(), None, &limits, to_text);
+            assert_eq!(result.is_ok(), ok);
+        }
+
+        // A framed block with the wrong checksum is named.
+        let encoder = EncoderBuilder::new()
+            .build(&HashMap::from([('a', 2), ('b', 1)]))
+            .unwrap();
+        let mut bits = BitVec::new();
+        for token in "ab".chars() {
+            encoder.encode_token(&token, &mut bits).unwrap();
+        }
+        let mut framed = Vec::new();
+        for (codebook, checksum) in [
+            (Some(encoder.codebook.clone()), crc32fast::hash(b"ab")),
+            (None, crc32fast::hash(b"ab") ^ 1),
+        ] {
+            let block = Frame::Block {
+                codebook,
+                bits: bits.clone(),
+                checksum,
+            };
+            write_frame(&mut framed, &block).unwrap();
+        }
+        let result = decompress_framed(
+            &framed[..],
+            &mut std::io::sink(),
+            None,
+            &DecodeLimits::default(),
+            to_text,
+        );
+        assert!(matches!(
+            result,
+            Err(Error::ChecksumMismatch { part: Some(part), .. }) if part == "block 1"
+        ));
+    }
+
+    #[test]
+    fn test_framed_length_mismatch_is_corrupt_data() {
+        let encoder = EncoderBuilder::new()
+            .build(&HashMap::from([('a', 2), ('b', 1)]))
+            .unwrap();
+        let mut bits = BitVec::new();
+        for token in "abba".chars() {
+            encoder.encode_token(&token, &mut bits).unwrap();
+        }
+        let block = Frame::Block {
+            codebook: Some(encoder.codebook.clone()),
+            bits: bits.clone(),
+            checksum: crc32fast::hash(b"abba"),
+        };
+        let to_text = |tokens: Vec<char>| tokens.into_iter().collect::<String>().into_bytes();
+
+        for (original_length, ok) in [(4, true), (5, false)] {
+            let mut framed = Vec::new();
+            write_frame(&mut framed, &block).unwrap();
+            let end = Frame::<char>::End {
+                original_length,
+                checksum: crc32fast::hash(b"abba"),
+            };
+            write_frame(&mut framed, &end).unwrap();
+            let result = decompress_framed(
+                &framed[..],
+                &mut std::io::sink(),
+                None,
+                &DecodeLimits::default(),
+                to_text,
+            );
+            assert_eq!(result.is_ok(), ok);
+        }
+
+        // A block which ends partway through a code: a = 0 and b = 10, so "abba" loses the last a
+        // and half of the last b.
+        bits.truncate(bits.len() - 2);
+        let mut framed = Vec::new();
+        write_frame(&mut framed, &block).unwrap();
+        let block = Frame::<char>::Block {
+            codebook: None,
+            bits,
+            checksum: 0,
+        };
+        write_frame(&mut framed, &block).unwrap();
+        let result = decompress_framed(
+            &framed[..],
+            &mut std::io::sink(),
+            None,
+            &DecodeLimits::default(),
+            to_text,
+        );
+        assert!(matches!(
+            result,
+            Err(Error::CorruptData(reason)) if reason.starts_with("block 1:")
+        ));
+    }
 }
@@ -0,0 +1,159 @@
+use crate::Error;
+use bit_vec::BitVec;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Encoded text packed into a single bit stream, without padding between lines.
+///
+/// The stream is divided into blocks which start on a token boundary, so each block can be decoded
+/// independently of the others.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Bitstream {
+    #[serde(with = "packed_bits")]
+    pub bits: BitVec,
+    /// Bit offset of the end of each block. Each block starts where the previous one ends.
+    pub block_ends: Vec<u64>,
+    /// CRC32 of the original bytes of each block, or empty if the blocks are not checksummed.
+    pub block_checksums: Vec<u32>,
+}
+
+impl Bitstream {
+    /// Concatenate independently encoded blocks, recording where each one ends. The blocks are not
+    /// checksummed.
+    pub fn from_blocks(blocks: Vec<BitVec>) -> Self {
+        let mut bits = BitVec::with_capacity(blocks.iter().map(BitVec::len).sum());
+        let mut block_ends = Vec::with_capacity(blocks.len());
+        for mut block in blocks {
+            bits.append(&mut block);
+            block_ends.push(bits.len() as u64);
+        }
+        Bitstream {
+            bits,
+            block_ends,
+            block_checksums: Vec::new(),
+        }
+    }
+
+    /// Check that the blocks lie within the stream and cover all of it, as read from untrusted
+    /// input.
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut start = 0;
+        for (block, &end) in self.block_ends.iter().enumerate() {
+            if end < start || end > self.bits.len() as u64 {
+                return Err(Error::CorruptData(format!(
+                    "block {block} ends at bit {end}, outside the {} bits from {start}",
+                    self.bits.len()
+                )));
+            }
+            start = end;
+        }
+        // Otherwise bits after the last block would be ignored without an error.
+        if start != self.bits.len() as u64 {
+            return Err(Error::CorruptData(format!(
+                "{} bits after the last block, at bit {start}, are not in any block",
+                self.bits.len() as u64 - start
+            )));
+        }
+        if !self.block_checksums.is_empty() && self.block_checksums.len() != self.block_ends.len() {
+            return Err(Error::CorruptData(format!(
+                "{} block checksums for {} blocks",
+                self.block_checksums.len(),
+                self.block_ends.len()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Range of bits occupied by each block.
+    pub fn block_ranges(&self) -> Vec<Range<usize>> {
+        let mut start = 0;
+        self.block_ends
+            .iter()
+            .map(|&end| {
+                let range = start..end as usize;
+                start = end as usize;
+                range
+            })
+            .collect()
+    }
+}
+
+/// Serializes a `BitVec` as its length followed by its bytes, rather than as a sequence of
+/// integers.
+pub(crate) mod packed_bits {
+    use bit_vec::BitVec;
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub fn serialize<S: Serializer>(bits: &BitVec, serializer: S) -> Result<S::Ok, S::Error> {
+        (bits.len() as u64, Bytes::new(&bits.to_bytes())).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BitVec, D::Error> {
+        let (len, bytes): (u64, ByteBuf) = Deserialize::deserialize(deserializer)?;
+        let mut bits = BitVec::from_bytes(&bytes);
+        if len > bits.len() as u64 {
+            return Err(de::Error::custom(format!(
+                "bit stream claims {len} bits but holds {}",
+                bits.len()
+            )));
+        }
+        bits.truncate(len as usize);
+        Ok(bits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(s: &str) -> BitVec {
+        s.chars().map(|c| c == '1').collect()
+    }
+
+    #[test]
+    fn test_from_blocks_records_block_ranges() {
+        let stream = Bitstream::from_blocks(vec![bits("101"), bits(""), bits("0011111111")]);
+        assert_eq!(stream.bits, bits("1010011111111"));
+        assert_eq!(stream.block_ranges(), vec![0..3, 3..3, 3..13]);
+    }
+
+    #[test]
+    fn test_validate_rejects_blocks_outside_stream() {
+        let mut stream = Bitstream::from_blocks(vec![bits("101"), bits("0011")]);
+        assert!(stream.validate().is_ok());
+        stream.block_ends = vec![3, 8];
+        assert!(stream.validate().is_err());
+        stream.block_ends = vec![5, 3];
+        assert!(stream.validate().is_err());
+        stream.block_ends = vec![3, 7];
+        stream.block_checksums = vec![0];
+        assert!(stream.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_bits_after_last_block() {
+        let mut stream = Bitstream::from_blocks(vec![bits("101"), bits("0011")]);
+        stream.bits.push(true);
+        assert!(matches!(stream.validate(), Err(Error::CorruptData(_))));
+
+        let stream = Bitstream {
+            bits: bits("1"),
+            block_ends: Vec::new(),
+            block_checksums: Vec::new(),
+        };
+        assert!(matches!(stream.validate(), Err(Error::CorruptData(_))));
+        assert!(Bitstream::from_blocks(Vec::new()).validate().is_ok());
+    }
+
+    #[test]
+    fn test_serialized_as_bytes() {
+        let stream = Bitstream::from_blocks(vec![bits("1111111111111"), bits("0101")]);
+        let serialized = rmp_serde::encode::to_vec(&stream).unwrap();
+        // Three bytes of data, rather than one integer per 32 bit block.
+        assert!(serialized.len() < 13);
+
+        let deserialized: Bitstream = rmp_serde::decode::from_slice(&serialized).unwrap();
+        assert_eq!(deserialized, stream);
+    }
+}
@@ -0,0 +1,44 @@
+use crate::Error;
+use crc32fast::Hasher;
+
+/// CRC32 of the lines, as if they were concatenated.
+pub fn of_lines<L: AsRef<[u8]>>(lines: &[L]) -> u32 {
+    let mut hasher = Hasher::new();
+    for line in lines {
+        hasher.update(line.as_ref());
+    }
+    hasher.finalize()
+}
+
+/// Check that decoded text has the checksum recorded for the original.
+pub fn verify(expected: u32, text: &[u8]) -> Result<(), Error> {
+    check(expected, crc32fast::hash(text))
+}
+
+/// Fail with `Error::ChecksumMismatch` unless the checksums are equal.
+pub fn check(expected: u32, actual: u32) -> Result<(), Error> {
+    if expected != actual {
+        return Err(Error::ChecksumMismatch {
+            part: None,
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lines_are_checksummed_as_one_text() {
+        let lines = ["abc\n", "", "de"];
+        assert_eq!(of_lines(&lines), crc32fast::hash(b"abc\nde"));
+        assert!(verify(of_lines(&lines), b"abc\nde").is_ok());
+        assert!(matches!(
+            verify(of_lines(&lines), b"abc\ndf"),
+            Err(Error::ChecksumMismatch { .. })
+        ));
+    }
+}
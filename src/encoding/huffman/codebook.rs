@@ -0,0 +1,380 @@
+use crate::{
+    encoding::huffman::{
+        deserialize_limited, encoder::MAX_CODE_LENGTH, limits::LimitedReader, DecodeLimits,
+    },
+    Error,
+};
+use bit_vec::BitVec;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::io::Read;
+
+/// A symbol of a code: either a token, or the escape code which is written before the literal
+/// form of a token missing from the codebook.
+///
+/// Tokens sort before the escape, so the escape comes last among the symbols with the same code
+/// length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Symbol<T> {
+    Token(T),
+    Escape,
+}
+
+/// Compact description of a canonical Huffman code.
+///
+/// Only the code length of each symbol is stored. Symbols are kept in canonical order (shortest
+/// code first, ties broken by symbol order), which is enough to rebuild every code.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Codebook<T> {
+    pub symbols: Vec<T>,
+    pub lengths: Vec<u8>,
+    /// Code length of the escape symbol, if the code has one.
+    pub escape: Option<u8>,
+}
+
+impl<T: Ord> Codebook<T> {
+    /// Build a codebook from the code length of each symbol, without an escape code.
+    pub fn from_code_lengths(code_lengths: impl IntoIterator<Item = (T, u8)>) -> Self {
+        Codebook::from_symbol_lengths(
+            code_lengths
+                .into_iter()
+                .map(|(token, length)| (Symbol::Token(token), length)),
+        )
+    }
+
+    /// Build a codebook from the code length of each symbol, which may include the escape.
+    pub fn from_symbol_lengths(code_lengths: impl IntoIterator<Item = (Symbol<T>, u8)>) -> Self {
+        let mut token_lengths: Vec<(T, u8)> = Vec::new();
+        let mut escape = None;
+        for (symbol, length) in code_lengths {
+            match symbol {
+                Symbol::Token(token) => token_lengths.push((token, length)),
+                Symbol::Escape => escape = Some(length),
+            }
+        }
+        token_lengths.sort_by(|(a, a_len), (b, b_len)| a_len.cmp(b_len).then_with(|| a.cmp(b)));
+        let (symbols, lengths) = token_lengths.into_iter().unzip();
+        Codebook {
+            symbols,
+            lengths,
+            escape,
+        }
+    }
+
+    /// Check that a codebook read from untrusted input describes a usable code, before decoding
+    /// with it.
+    ///
+    /// Every code must be between 1 and `limits.max_code_length` bits long, the tokens must be in
+    /// canonical order without duplicates, and the code lengths must satisfy the Kraft inequality.
+    /// Canonical codes assigned to lengths which satisfy it are always prefix-free.
+    pub fn validate(&self, limits: &DecodeLimits) -> Result<(), Error> {
+        let invalid =
+            |reason: String| Err(Error::CorruptData(format!("invalid codebook: {reason}")));
+
+        if self.symbols.len() != self.lengths.len() {
+            return invalid(format!(
+                "{} tokens but {} code lengths",
+                self.symbols.len(),
+                self.lengths.len()
+            ));
+        }
+        let symbol_count = self.symbols.len() + usize::from(self.escape.is_some());
+        if symbol_count > limits.max_symbols {
+            return invalid(format!(
+                "{symbol_count} symbols, more than the limit of {}",
+                limits.max_symbols
+            ));
+        }
+
+        // Each code of `length` bits takes up 2^-length of the code space, counted here in units
+        // of 2^-64.
+        let mut code_space: u128 = 0;
+        for &length in self.lengths.iter().chain(&self.escape) {
+            if length == 0 {
+                return invalid(String::from("empty code"));
+            }
+            if length > limits.max_code_length.min(MAX_CODE_LENGTH) {
+                return invalid(format!(
+                    "code of {length} bits, longer than the limit of {}",
+                    limits.max_code_length.min(MAX_CODE_LENGTH)
+                ));
+            }
+            code_space += 1 << (64 - length);
+        }
+        if code_space > 1 << 64 {
+            return invalid(String::from(
+                "code lengths do not satisfy the Kraft inequality",
+            ));
+        }
+
+        let entries: Vec<(&u8, &T)> = self.lengths.iter().zip(&self.symbols).collect();
+        if entries.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return invalid(String::from("tokens are not in canonical order"));
+        }
+        let mut tokens: Vec<&T> = self.symbols.iter().collect();
+        tokens.sort_unstable();
+        if tokens.windows(2).any(|pair| pair[0] == pair[1]) {
+            return invalid(String::from("duplicate token"));
+        }
+        Ok(())
+    }
+}
+
+impl<T: Ord + DeserializeOwned> Codebook<T> {
+    /// Read a MessagePack encoded codebook, such as the body of a codebook file, and check that it
+    /// describes a usable code. A malformed codebook fails with `Error::CorruptData`, so it never
+    /// reaches the encoder or decoder.
+    ///
+    /// The codebook is limited to `limits.max_body_size` bytes, and checked against the other
+    /// limits, as the body of a compressed file is.
+    pub fn read_from<R: Read>(reader: R, limits: &DecodeLimits) -> Result<Self, Error> {
+        let mut deserializer =
+            rmp_serde::Deserializer::new(LimitedReader::new(reader, limits.max_body_size));
+        let codebook: Codebook<T> = deserialize_limited(&mut deserializer, limits)?;
+        codebook.validate(limits)?;
+        Ok(codebook)
+    }
+}
+
+impl<T> Codebook<T> {
+    /// Every symbol and its code length, in canonical order.
+    pub fn entries(&self) -> impl Iterator<Item = (Symbol<&T>, u8)> {
+        // The escape goes after every token with a code of the same length or shorter.
+        let escape_index = self
+            .escape
+            .map(|escape| self.lengths.partition_point(|&length| length <= escape));
+        let mut tokens = self.symbols.iter().zip(self.lengths.iter().copied());
+        (0..self.symbols.len() + usize::from(self.escape.is_some())).map(move |i| {
+            if Some(i) == escape_index {
+                (Symbol::Escape, self.escape.unwrap())
+            } else {
+                let (token, length) = tokens.next().unwrap();
+                (Symbol::Token(token), length)
+            }
+        })
+    }
+
+    /// Assign canonical codes to the symbols, in canonical order.
+    ///
+    /// The first symbol gets a code of all zeros. Each following code is the previous code plus
+    /// one, shifted left whenever the code length increases.
+    pub fn codes(&self) -> impl Iterator<Item = (Symbol<&T>, BitVec)> {
+        let mut code = BitVec::new();
+        self.entries()
+            .enumerate()
+            .map(move |(i, (symbol, length))| {
+                if i > 0 {
+                    increment(&mut code);
+                }
+                code.grow(usize::from(length) - code.len(), false);
+                (symbol, code.clone())
+            })
+    }
+
+    /// Length of the longest code.
+    pub fn max_length(&self) -> u8 {
+        let longest_token = self.lengths.last().copied().unwrap_or(0);
+        longest_token.max(self.escape.unwrap_or(0))
+    }
+
+    /// Number of tokens, not counting the escape.
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+}
+
+/// Add one to a big endian binary number, in place.
+fn increment(code: &mut BitVec) {
+    for i in (0..code.len()).rev() {
+        if code[i] {
+            code.set(i, false);
+        } else {
+            code.set(i, true);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(s: &str) -> BitVec {
+        s.chars().map(|c| c == '1').collect()
+    }
+
+    #[test]
+    fn test_codes_are_canonical() {
+        // Example from RFC 1951, section 3.2.2.
+        let codebook = Codebook::from_code_lengths([
+            ('A', 3),
+            ('B', 3),
+            ('C', 3),
+            ('D', 3),
+            ('E', 3),
+            ('F', 2),
+            ('G', 4),
+            ('H', 4),
+        ]);
+        assert_eq!(
+            codebook.symbols,
+            vec!['F', 'A', 'B', 'C', 'D', 'E', 'G', 'H']
+        );
+        assert_eq!(codebook.lengths, vec![2, 3, 3, 3, 3, 3, 4, 4]);
+
+        let codes: Vec<(Symbol<&char>, BitVec)> = codebook.codes().collect();
+        assert_eq!(
+            codes,
+            vec![
+                (Symbol::Token(&'F'), bits("00")),
+                (Symbol::Token(&'A'), bits("010")),
+                (Symbol::Token(&'B'), bits("011")),
+                (Symbol::Token(&'C'), bits("100")),
+                (Symbol::Token(&'D'), bits("101")),
+                (Symbol::Token(&'E'), bits("110")),
+                (Symbol::Token(&'G'), bits("1110")),
+                (Symbol::Token(&'H'), bits("1111")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_symbol_order_breaks_ties() {
+        let codebook = Codebook::from_code_lengths([
+            (String::from("world "), 2),
+            (String::from("hello "), 2),
+            (String::from("!"), 1),
+        ]);
+        assert_eq!(codebook.symbols, vec!["!", "hello ", "world "]);
+        assert_eq!(codebook.lengths, vec![1, 2, 2]);
+    }
+
+    #[test]
+    fn test_validate_accepts_built_codebooks() {
+        let limits = DecodeLimits::default();
+        let codebook = Codebook::from_symbol_lengths([
+            (Symbol::Token('a'), 1),
+            (Symbol::Token('b'), 2),
+            (Symbol::Escape, 2),
+        ]);
+        assert!(codebook.validate(&limits).is_ok());
+        assert!(Codebook::<char>::from_code_lengths([])
+            .validate(&limits)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_validate_rejects_malformed_codebooks() {
+        let limits = DecodeLimits::default();
+        let codebook = |symbols: &str, lengths: &[u8], escape| Codebook {
+            symbols: symbols.chars().collect(),
+            lengths: lengths.to_vec(),
+            escape,
+        };
+        let rejected = [
+            // Not prefix-free: three codes of one bit.
+            codebook("abc", &[1, 1, 1], None),
+            codebook("ab", &[1, 1], Some(1)),
+            // Empty code.
+            codebook("ab", &[0, 1], None),
+            codebook("a", &[1], Some(0)),
+            // Too long.
+            codebook("ab", &[1, 65], None),
+            // Out of order, or duplicated.
+            codebook("ba", &[1, 1], None),
+            codebook("ab", &[2, 1], None),
+            codebook("aa", &[1, 1], None),
+            codebook("aba", &[1, 2, 2], None),
+            // Mismatched lengths.
+            codebook("ab", &[1], None),
+        ];
+        for codebook in rejected {
+            assert!(
+                matches!(codebook.validate(&limits), Err(Error::CorruptData(_))),
+                "{codebook:?} was accepted"
+            );
+        }
+
+        let limits = DecodeLimits {
+            max_symbols: 2,
+            max_code_length: 2,
+            ..DecodeLimits::default()
+        };
+        assert!(codebook("ab", &[1, 2], Some(2)).validate(&limits).is_err());
+        assert!(codebook("abc", &[1, 2, 3], None).validate(&limits).is_err());
+    }
+
+    #[test]
+    fn test_read_from_rejects_malformed_codebooks() {
+        let good = Codebook::from_symbol_lengths([
+            (Symbol::Token('e'), 1),
+            (Symbol::Token('h'), 2),
+            (Symbol::Escape, 2),
+        ]);
+        let bytes = rmp_serde::encode::to_vec(&good).unwrap();
+        assert_eq!(
+            Codebook::<char>::read_from(&bytes[..], &DecodeLimits::default()).unwrap(),
+            good
+        );
+
+        // Out of canonical order, which would make the encoder build codes shorter than the ones
+        // before them.
+        let bad = Codebook {
+            symbols: vec!['h', 'e'],
+            lengths: vec![3, 1],
+            escape: Some(2),
+        };
+        let bytes = rmp_serde::encode::to_vec(&bad).unwrap();
+        assert!(matches!(
+            Codebook::<char>::read_from(&bytes[..], &DecodeLimits::default()),
+            Err(Error::CorruptData(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_from_limits_the_codebook_size() {
+        let codebook = Codebook::from_code_lengths((0..=255u8).map(|byte| (byte, 8)));
+        let bytes = rmp_serde::encode::to_vec(&codebook).unwrap();
+        let limits = DecodeLimits {
+            max_body_size: bytes.len() as u64 - 1,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            Codebook::<u8>::read_from(&bytes[..], &limits),
+            Err(Error::InvalidFormat(_))
+        ));
+
+        let limits = DecodeLimits {
+            max_body_size: bytes.len() as u64,
+            ..DecodeLimits::default()
+        };
+        assert_eq!(Codebook::read_from(&bytes[..], &limits).unwrap(), codebook);
+    }
+
+    #[test]
+    fn test_escape_follows_tokens_of_the_same_length() {
+        let codebook = Codebook::from_symbol_lengths([
+            (Symbol::Token('a'), 1),
+            (Symbol::Escape, 3),
+            (Symbol::Token('b'), 3),
+            (Symbol::Token('c'), 2),
+        ]);
+        assert_eq!(codebook.symbols, vec!['a', 'c', 'b']);
+        assert_eq!(codebook.escape, Some(3));
+        assert_eq!(codebook.max_length(), 3);
+
+        let codes: Vec<(Symbol<&char>, BitVec)> = codebook.codes().collect();
+        assert_eq!(
+            codes,
+            vec![
+                (Symbol::Token(&'a'), bits("0")),
+                (Symbol::Token(&'c'), bits("10")),
+                (Symbol::Token(&'b'), bits("110")),
+                (Symbol::Escape, bits("111")),
+            ]
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use crate::{
+    encoding::huffman::{
+        bitstream::Bitstream,
+        checksum,
+        codebook::{Codebook, Symbol},
+        literal::{self, Literal},
+    },
+    Error,
+};
+use bit_vec::BitVec;
+use rayon::prelude::*;
+use std::ops::Range;
+
+/// Number of bits resolved by a single table lookup. Longer codes fall back to a search over the
+/// canonical code ranges.
+const LOOKUP_BITS: usize = 10;
+
+#[derive(Clone, Copy, Default)]
+struct LookupEntry {
+    symbol: u32,
+    /// Length of the code, or zero if no code of at most `LOOKUP_BITS` bits matches.
+    length: u8,
+}
+
+/// The canonical codes of a single length, which are consecutive integers.
+#[derive(Clone, Copy, Default)]
+struct CodeRange {
+    first_code: u64,
+    first_symbol: usize,
+    count: u64,
+}
+
+/// Decodes canonical Huffman codes a whole symbol at a time.
+///
+/// The next `LOOKUP_BITS` bits of input index a table which holds the symbol and length of every
+/// code short enough to fit. Codes which do not fit are resolved by comparing the input against the
+/// range of canonical codes of each length.
+pub struct HuffmanDecoder<T> {
+    /// Symbols in canonical order, including the escape.
+    symbols: Vec<Symbol<T>>,
+    lookup: Vec<LookupEntry>,
+    /// Indexed by code length.
+    ranges: Vec<CodeRange>,
+}
+
+impl<T> HuffmanDecoder<T>
+where
+    T: Clone + Send + Sync,
+{
+    /// Build the decoding tables for the canonical codes described by a codebook.
+    ///
+    /// The codebook must be valid, which codebooks built by the encoder always are. Check one read
+    /// from untrusted input with `Codebook::validate` first.
+    pub fn from_codebook(codebook: Codebook<T>) -> Self {
+        let max_length = codebook.max_length();
+        let mut lookup = vec![LookupEntry::default(); 1 << LOOKUP_BITS];
+        let mut ranges = vec![CodeRange::default(); usize::from(max_length) + 1];
+
+        let mut symbols = Vec::with_capacity(codebook.len() + 1);
+        let mut code: u64 = 0;
+        let mut previous_length = 0;
+        for (symbol, (entry, length)) in codebook.entries().enumerate() {
+            symbols.push(match entry {
+                Symbol::Token(token) => Symbol::Token(token.clone()),
+                Symbol::Escape => Symbol::Escape,
+            });
+            if symbol > 0 {
+                code += 1;
+            }
+            // Only the first code can grow by 64 bits, from an empty code, and it is zero.
+            code = code
+                .checked_shl(u32::from(length - previous_length))
+                .unwrap_or(0);
+            previous_length = length;
+
+            let range = &mut ranges[usize::from(length)];
+            if range.count == 0 {
+                range.first_code = code;
+                range.first_symbol = symbol;
+            }
+            range.count += 1;
+
+            let length = usize::from(length);
+            if length > 0 && length <= LOOKUP_BITS {
+                // The table is indexed by bits in stream order, so the code is stored reversed and
+                // every possible value of the bits following it maps to the same entry.
+                let reversed = reverse_bits(code, length);
+                for suffix in 0..1 << (LOOKUP_BITS - length) {
+                    lookup[reversed | (suffix << length)] = LookupEntry {
+                        symbol: symbol as u32,
+                        length: length as u8,
+                    };
+                }
+            }
+        }
+
+        HuffmanDecoder {
+            symbols,
+            lookup,
+            ranges,
+        }
+    }
+}
+
+impl<T> HuffmanDecoder<T>
+where
+    T: Clone + Send + Sync + Literal,
+{
+    /// Decode each of the lines in parallel. A corrupt line fails with an error naming it.
+    pub fn decode(
+        &self,
+        input: &[BitVec],
+        tokens_to_line: impl Fn(Vec<T>) -> Vec<u8> + Send + Sync,
+    ) -> Result<Vec<u8>, Error> {
+        Ok(input
+            .par_iter()
+            .enumerate()
+            .map(|(line, bits)| {
+                let tokens = self
+                    .decode_bits(bits)
+                    .map_err(|e| e.in_part(format!("line {}", line + 1)))?;
+                Ok(tokens_to_line(tokens))
+            })
+            .collect::<Result<Vec<Vec<u8>>, Error>>()?
+            .concat())
+    }
+
+    /// Decode the blocks of a bit stream in parallel. A corrupt block fails with an error naming
+    /// it, and the text of each block is checked against its checksum, if the stream has them.
+    pub fn decode_stream(
+        &self,
+        stream: &Bitstream,
+        tokens_to_text: impl Fn(Vec<T>) -> Vec<u8> + Send + Sync,
+    ) -> Result<Vec<u8>, Error> {
+        Ok(stream
+            .block_ranges()
+            .into_par_iter()
+            .enumerate()
+            .map(|(block, range)| {
+                let tokens = self
+                    .decode_range(&stream.bits, range)
+                    .map_err(|e| e.in_part(format!("block {block}")))?;
+                let text = tokens_to_text(tokens);
+                if let Some(&expected) = stream.block_checksums.get(block) {
+                    checksum::verify(expected, &text)
+                        .map_err(|e| e.in_part(format!("block {block}")))?;
+                }
+                Ok(text)
+            })
+            .collect::<Result<Vec<Vec<u8>>, Error>>()?
+            .concat())
+    }
+
+    /// Decode the tokens in a bit vector, which must hold only complete codes and literals.
+    pub fn decode_bits(&self, bits: &BitVec) -> Result<Vec<T>, Error> {
+        self.decode_range(bits, 0..bits.len())
+    }
+
+    /// Decode the tokens whose codes lie within `range` of a bit vector.
+    ///
+    /// Fails with `Error::CorruptData` if the bits do not match a code, or if the range ends
+    /// partway through a code or literal.
+    pub fn decode_range(&self, bits: &BitVec, range: Range<usize>) -> Result<Vec<T>, Error> {
+        let mut tokens = Vec::new();
+        let mut position = range.start;
+        while position < range.end {
+            let (symbol, length) = self.next_symbol(bits, position, range.end)?;
+            position += length;
+            match &self.symbols[symbol] {
+                Symbol::Token(token) => tokens.push(token.clone()),
+                Symbol::Escape => {
+                    let (token, length) = literal::read_literal(bits, position, range.end)
+                        .ok_or_else(|| {
+                            Error::CorruptData(format!(
+                                "escaped literal at bit {} is truncated or invalid",
+                                position - range.start
+                            ))
+                        })?;
+                    tokens.push(token);
+                    position += length;
+                }
+            }
+        }
+        Ok(tokens)
+    }
+
+    /// Find the symbol whose code starts at `position` and finishes before `end`, returning its
+    /// index and code length.
+    fn next_symbol(
+        &self,
+        bits: &BitVec,
+        position: usize,
+        end: usize,
+    ) -> Result<(usize, usize), Error> {
+        let remaining = end - position;
+        let leftover = || {
+            Error::CorruptData(format!(
+                "{remaining} bits left over at the end, which are not a complete code"
+            ))
+        };
+
+        let entry = self.lookup[peek(bits, position, LOOKUP_BITS)];
+        if entry.length > 0 {
+            let length = usize::from(entry.length);
+            return match length <= remaining {
+                true => Ok((entry.symbol as usize, length)),
+                false => Err(leftover()),
+            };
+        }
+
+        let mut code = 0;
+        for (length, range) in self.ranges.iter().enumerate().skip(1) {
+            if length > remaining {
+                return Err(leftover());
+            }
+            code = (code << 1) | u64::from(bits[position + length - 1]);
+            if let Some(offset) = code.checked_sub(range.first_code) {
+                if offset < range.count {
+                    return Ok((range.first_symbol + offset as usize, length));
+                }
+            }
+        }
+        Err(Error::CorruptData(String::from(
+            "bits do not match any code",
+        )))
+    }
+}
+
+/// Read up to 32 bits starting at `position`, with the first bit in the least significant place.
+/// Bits past the end of the vector read as zero.
+fn peek(bits: &BitVec, position: usize, count: usize) -> usize {
+    let storage = bits.storage();
+    let block = position / 32;
+    let offset = position % 32;
+    let mut window = u64::from(storage.get(block).copied().unwrap_or(0)) >> offset;
+    if let Some(&next) = storage.get(block + 1) {
+        window |= u64::from(next) << (32 - offset);
+    }
+    (window & ((1 << count) - 1)) as usize
+}
+
+fn reverse_bits(code: u64, length: usize) -> usize {
+    (code.reverse_bits() >> (64 - length)) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::huffman::HuffmanEncoder;
+
+    #[test]
+    fn test_decodes_short_and_long_codes() {
+        // Lengths 1 to 14 and 14 again, so most codes are longer than a single table lookup.
+        let codebook =
+            Codebook::from_code_lengths((0..15u8).map(|i| (i, if i == 14 { 14 } else { i + 1 })));
+        let encoder = HuffmanEncoder::from_codebook(codebook.clone());
+        let decoder = HuffmanDecoder::from_codebook(codebook);
+
+        let tokens: Vec<u8> = (0..300).map(|i: u32| ((i * 7919) % 15) as u8).collect();
+        let mut bits = BitVec::new();
+        for token in &tokens {
+            bits.extend(encoder.encoder[token].iter());
+        }
+
+        assert_eq!(decoder.decode_bits(&bits).unwrap(), tokens);
+    }
+
+    #[test]
+    fn test_incomplete_trailing_code_is_corrupt_data() {
+        let codebook = Codebook::from_code_lengths([('a', 1), ('b', 2), ('c', 2)]);
+        let decoder = HuffmanDecoder::from_codebook(codebook);
+
+        // a = 0, b = 10, c = 11
+        let bits: BitVec = [false, true, true].into_iter().collect();
+        assert_eq!(decoder.decode_bits(&bits).unwrap(), vec!['a', 'c']);
+        let bits: BitVec = [false, true, true, true].into_iter().collect();
+        assert!(matches!(
+            decoder.decode_bits(&bits),
+            Err(Error::CorruptData(reason)) if reason.contains("1 bits left over")
+        ));
+    }
+
+    #[test]
+    fn test_unassigned_code_is_corrupt_data() {
+        // Only the code 0 is assigned.
+        let decoder = HuffmanDecoder::from_codebook(Codebook::from_code_lengths([('a', 1)]));
+        let bits: BitVec = [false, true, false].into_iter().collect();
+        assert!(matches!(
+            decoder.decode_bits(&bits),
+            Err(Error::CorruptData(_))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_literal_is_corrupt_data() {
+        let codebook =
+            Codebook::from_symbol_lengths([(Symbol::Token('a'), 1), (Symbol::Escape, 1)]);
+        let encoder = HuffmanEncoder::from_codebook(codebook.clone());
+        let decoder = HuffmanDecoder::from_codebook(codebook);
+
+        let mut bits = BitVec::new();
+        encoder.encode_token(&'😆', &mut bits).unwrap();
+        bits.truncate(bits.len() - 3);
+        assert!(matches!(
+            decoder.decode_bits(&bits),
+            Err(Error::CorruptData(_))
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_line_is_named() {
+        let codebook = Codebook::from_code_lengths([('a', 1), ('b', 2), ('c', 2)]);
+        let decoder = HuffmanDecoder::from_codebook(codebook);
+
+        let lines: Vec<BitVec> = vec![
+            [false].into_iter().collect(),
+            [true, false, true].into_iter().collect(),
+        ];
+        let to_text = |tokens: Vec<char>| tokens.into_iter().collect::<String>().into_bytes();
+        assert!(matches!(
+            decoder.decode(&lines, to_text),
+            Err(Error::CorruptData(reason)) if reason.starts_with("line 2:")
+        ));
+    }
+
+    #[test]
+    fn test_decodes_escape_with_long_code() {
+        let codebook = Codebook::from_symbol_lengths(
+            (0..12u8)
+                .map(|i| (Symbol::Token(i), i + 1))
+                .chain([(Symbol::Escape, 12)]),
+        );
+        let encoder = HuffmanEncoder::from_codebook(codebook.clone());
+        let decoder = HuffmanDecoder::from_codebook(codebook);
+        assert_eq!(encoder.escape.as_ref().map(BitVec::len), Some(12));
+
+        let tokens: Vec<u8> = vec![0, 200, 11, 5, 255, 0];
+        let mut bits = BitVec::new();
+        for token in &tokens {
+            encoder.encode_token(token, &mut bits).unwrap();
+        }
+        assert_eq!(decoder.decode_bits(&bits).unwrap(), tokens);
+    }
+}
@@ -1,4 +1,12 @@
-use crate::encoding::huffman::tree::HuffmanTree;
+use crate::{
+    encoding::huffman::{
+        codebook::{Codebook, Symbol},
+        literal::{self, Literal},
+        package_merge,
+        tree::HuffmanTree,
+    },
+    Error,
+};
 use bit_vec::BitVec;
 use rayon::prelude::*;
 use std::{collections::HashMap, hash::Hash};
@@ -9,41 +17,64 @@ where
     T: Hash + Eq + Sync,
 {
     pub encoder: HashMap<T, BitVec>,
-    pub decoder: HashMap<BitVec, T>,
+    /// Code written before the literal form of a token missing from `encoder`.
+    pub escape: Option<BitVec>,
+    pub codebook: Codebook<T>,
 }
 
 impl<T> HuffmanEncoder<T>
 where
-    T: Hash + Eq + Clone + Send + Sync,
+    T: Hash + Eq + Ord + Clone + Send + Sync,
 {
+    /// Build an encoder from the frequencies, with the default settings of `EncoderBuilder`.
+    pub fn from_frequencies(frequencies: &HashMap<T, u64>) -> Result<Self, Error> {
+        EncoderBuilder::new().build(frequencies)
+    }
+
+    /// Assign canonical codes to the tokens in the tree, using the depth of each leaf as its code
+    /// length.
+    #[allow(clippy::boxed_local)]
     pub fn from_huffman_tree(tree: Box<HuffmanTree<T>>) -> Self {
-        let mut encoder = HashMap::new();
-        let mut decoder = HashMap::new();
-        HuffmanEncoder::get_encoding_from_node(tree, BitVec::new(), &mut encoder, &mut decoder);
-        HuffmanEncoder { encoder, decoder }
-    }
-
-    fn get_encoding_from_node<'a>(
-        current_node: Box<HuffmanTree<T>>,
-        encoding: BitVec,
-        encoder: &mut HashMap<T, BitVec>,
-        decoder: &mut HashMap<BitVec, T>,
-    ) {
-        match *current_node {
-            HuffmanTree::Leaf { token, .. } => {
-                encoder.insert(token.clone(), encoding.clone());
-                decoder.insert(encoding.clone(), token.clone());
+        HuffmanEncoder::from_codebook(Codebook::from_code_lengths(tree.code_lengths()))
+    }
+
+    /// Rebuild the canonical codes described by a codebook.
+    pub fn from_codebook(codebook: Codebook<T>) -> Self {
+        let mut encoder = HashMap::with_capacity(codebook.len());
+        let mut escape = None;
+        for (symbol, code) in codebook.codes() {
+            match symbol {
+                Symbol::Token(token) => {
+                    encoder.insert(token.clone(), code);
+                }
+                Symbol::Escape => escape = Some(code),
             }
-            HuffmanTree::InternalNode { left, right, .. } => {
-                let mut left_encoding = encoding.clone();
-                left_encoding.push(false);
-                HuffmanEncoder::get_encoding_from_node(left, left_encoding, encoder, decoder);
-
-                let mut right_encoding = encoding.clone();
-                right_encoding.push(true);
-                HuffmanEncoder::get_encoding_from_node(right, right_encoding, encoder, decoder);
+        }
+        HuffmanEncoder {
+            encoder,
+            escape,
+            codebook,
+        }
+    }
+
+    /// Append the code for a token. Tokens missing from the codebook are written as the escape code
+    /// followed by their literal form.
+    ///
+    /// Fails with `Error::UnknownToken` if the token is missing from a codebook without an escape
+    /// code.
+    pub fn encode_token(&self, token: &T, bits: &mut BitVec) -> Result<(), Error>
+    where
+        T: Literal,
+    {
+        match (self.encoder.get(token), &self.escape) {
+            (Some(code), _) => bits.extend(code),
+            (None, Some(escape)) => {
+                bits.extend(escape);
+                literal::write_literal(token, bits);
             }
+            (None, None) => return Err(Error::UnknownToken(token.to_literal())),
         }
+        Ok(())
     }
 
     /// Use encoding derived from huffman tree to encode the lines.
@@ -51,78 +82,153 @@ where
         self,
         lines: &'a Vec<String>,
         get_tokens_from_line: impl Fn(&'a str) -> TokenIterator + Send + Sync,
-    ) -> Vec<BitVec>
+    ) -> Result<Vec<BitVec>, Error>
     where
+        T: Literal,
         TokenIterator: Iterator<Item = T>,
     {
         lines
             .par_iter()
             .map(|line| {
-                get_tokens_from_line(line)
-                    .map(|token| self.encoder.get(&token).unwrap().clone())
-                    .fold(BitVec::new(), |mut vec1, vec2| {
-                        vec1.extend(vec2);
-                        vec1
-                    })
+                get_tokens_from_line(line).try_fold(BitVec::new(), |mut bits, token| {
+                    self.encode_token(&token, &mut bits)?;
+                    Ok(bits)
+                })
             })
             .collect()
     }
+}
 
-    /// Use encoding derived from huffman tree to decode the lines.
-    pub fn decode(
-        decoder: HashMap<BitVec, T>,
-        input: &Vec<BitVec>,
-        tokens_to_line: impl Fn(Vec<T>) -> String + Send + Sync,
-    ) -> Vec<u8> {
-        input
-            .par_iter()
-            .map(|bits| {
-                let mut candidate = BitVec::new();
-                let mut tokens = Vec::new();
-                for bit in bits {
-                    candidate.push(bit);
-                    if let Some(entry) = decoder.get(&candidate) {
-                        tokens.push((*entry).clone());
-                        candidate = BitVec::new();
-                    }
-                }
-                tokens_to_line(tokens)
-            })
-            .collect::<String>()
-            .into_bytes()
+/// Largest code length the decoder supports.
+pub const MAX_CODE_LENGTH: u8 = 64;
+
+/// Code length limit used unless another is given.
+pub const DEFAULT_MAX_CODE_LENGTH: u8 = 32;
+
+/// Settings for building a `HuffmanEncoder` from token frequencies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncoderBuilder {
+    max_code_length: u8,
+    escape_count: u64,
+}
+
+impl Default for EncoderBuilder {
+    fn default() -> Self {
+        EncoderBuilder {
+            max_code_length: DEFAULT_MAX_CODE_LENGTH,
+            escape_count: 0,
+        }
+    }
+}
+
+impl EncoderBuilder {
+    pub fn new() -> Self {
+        EncoderBuilder::default()
+    }
+
+    /// Limit the length of every code to `max_code_length` bits.
+    ///
+    /// If there are too many distinct tokens to fit in codes of that length, the limit is raised
+    /// to the shortest length which fits them all.
+    ///
+    /// # Panics
+    ///
+    /// If `max_code_length` is zero or greater than `MAX_CODE_LENGTH`.
+    pub fn max_code_length(mut self, max_code_length: u8) -> Self {
+        assert!(
+            (1..=MAX_CODE_LENGTH).contains(&max_code_length),
+            "max code length must be between 1 and {MAX_CODE_LENGTH}"
+        );
+        self.max_code_length = max_code_length;
+        self
+    }
+
+    /// Count the escape symbol as occurring `escape_count` times, rather than never.
+    ///
+    /// Useful when the frequencies deliberately leave out rare tokens, such as a codebook trained
+    /// on a limited vocabulary, where the escape takes the place of every token left out.
+    pub fn escape_count(mut self, escape_count: u64) -> Self {
+        self.escape_count = escape_count;
+        self
+    }
+
+    /// Build a Huffman Tree from the frequencies, and assign canonical codes to its tokens.
+    ///
+    /// The tree also holds an escape symbol with a count of zero (unless set otherwise), so the
+    /// encoder can still encode tokens which were not counted, at the cost of making one of the
+    /// rarest codes a bit longer.
+    ///
+    /// If the tree is deeper than the code length limit, the code lengths are instead found with
+    /// the package-merge algorithm, which gives the best compression possible within the limit.
+    ///
+    /// Fails if the total count of tokens does not fit in a `u64`.
+    pub fn build<T>(&self, frequencies: &HashMap<T, u64>) -> Result<HuffmanEncoder<T>, Error>
+    where
+        T: Hash + Eq + Ord + Clone + Send + Sync,
+    {
+        let mut symbol_frequencies: HashMap<Symbol<&T>, u64> = frequencies
+            .iter()
+            .map(|(token, &count)| (Symbol::Token(token), count))
+            .collect();
+        symbol_frequencies.insert(Symbol::Escape, self.escape_count);
+
+        let tree = HuffmanTree::from_frequencies(&symbol_frequencies)?
+            .expect("the escape symbol is always present");
+        let mut code_lengths = tree.code_lengths();
+
+        let longest = code_lengths.iter().map(|&(_, length)| length).max();
+        if longest > Some(self.max_code_length) {
+            let max_code_length = self
+                .max_code_length
+                .max(package_merge::min_code_length(symbol_frequencies.len()));
+            code_lengths = package_merge::code_lengths(&symbol_frequencies, max_code_length);
+        }
+
+        let code_lengths = code_lengths.into_iter().map(|(symbol, length)| {
+            let symbol = match symbol {
+                Symbol::Token(token) => Symbol::Token(token.clone()),
+                Symbol::Escape => Symbol::Escape,
+            };
+            (symbol, length)
+        });
+        Ok(HuffmanEncoder::from_codebook(
+            Codebook::from_symbol_lengths(code_lengths),
+        ))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::encoding::huffman::HuffmanDecoder;
 
     #[test]
     fn test_from_huffman_tree() {
         let counts = HashMap::from([('a', 10), ('!', 38), ('😆', 12)]);
-        let tree = HuffmanTree::from_frequencies(&counts);
+        let tree = HuffmanTree::from_frequencies(&counts).unwrap().unwrap();
         let encoder = HuffmanEncoder::from_huffman_tree(tree);
+        let decoder = HuffmanDecoder::from_codebook(encoder.codebook.clone());
 
-        let expected_bits_for_a = [false, false];
-        let expected_bits_for_exclaim = [true];
-        let expected_bits_for_lols = [false, true];
+        let expected_bits_for_a = [true, false];
+        let expected_bits_for_exclaim = [false];
+        let expected_bits_for_lols = [true, true];
 
         let bits_for_a = encoder.encoder.get(&'a').unwrap();
         assert_eq!(bits_for_a.len(), 2);
         assert_eq!(bits_for_a[0], expected_bits_for_a[0]);
         assert_eq!(bits_for_a[1], expected_bits_for_a[1]);
-        assert_eq!(encoder.decoder.get(bits_for_a).unwrap(), &'a');
+        assert_eq!(decoder.decode_bits(bits_for_a).unwrap(), vec!['a']);
 
         let bits_for_exclaim = encoder.encoder.get(&'!').unwrap();
         assert_eq!(bits_for_exclaim.len(), 1);
         assert_eq!(bits_for_exclaim[0], expected_bits_for_exclaim[0]);
-        assert_eq!(encoder.decoder.get(bits_for_exclaim).unwrap(), &'!');
+        assert_eq!(decoder.decode_bits(bits_for_exclaim).unwrap(), vec!['!']);
 
         let bits_for_lols = encoder.encoder.get(&'😆').unwrap();
         assert_eq!(bits_for_lols.len(), 2);
         assert_eq!(bits_for_lols[0], expected_bits_for_lols[0]);
         assert_eq!(bits_for_lols[1], expected_bits_for_lols[1]);
-        assert_eq!(encoder.decoder.get(bits_for_lols).unwrap(), &'😆');
+        assert_eq!(decoder.decode_bits(bits_for_lols).unwrap(), vec!['😆']);
     }
 
     #[test]
@@ -140,18 +246,130 @@ mod tests {
             ('😆', 1),
             ('\n', 1),
         ]);
-        let tree = HuffmanTree::from_frequencies(&counts);
+        let tree = HuffmanTree::from_frequencies(&counts).unwrap().unwrap();
         let encoder = HuffmanEncoder::from_huffman_tree(tree);
         let input = "!!hi!\na!😆\n12aA|Z";
         let input_lines: Vec<String> = input.split_inclusive('\n').map(String::from).collect();
-        let encoded_text = encoder.clone().encode(&input_lines, |line| line.chars());
+        let decoder = HuffmanDecoder::from_codebook(encoder.codebook.clone());
+        let encoded_text = encoder.encode(&input_lines, |line| line.chars()).unwrap();
         assert_eq!(
             input.as_bytes(),
-            HuffmanEncoder::decode(
-                encoder.decoder.clone(),
-                &encoded_text,
-                |tokens: Vec<char>| tokens.into_iter().collect::<String>()
-            )
+            decoder
+                .decode(&encoded_text, |tokens: Vec<char>| tokens
+                    .into_iter()
+                    .collect::<String>()
+                    .into_bytes())
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_equal_counts_give_deeper_codes_to_earlier_tokens() {
+        let counts = HashMap::from([('e', 2), ('d', 1), ('c', 1), ('b', 1), ('a', 1)]);
+        let encoder = HuffmanEncoder::from_frequencies(&counts).unwrap();
+        assert_eq!(
+            encoder.codebook,
+            Codebook::from_symbol_lengths([
+                (Symbol::Token('a'), 3),
+                (Symbol::Token('b'), 3),
+                (Symbol::Token('c'), 3),
+                (Symbol::Token('d'), 2),
+                (Symbol::Token('e'), 2),
+                (Symbol::Escape, 3),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_builder_limits_code_length() {
+        // Counts which double for each token give a tree with one token at every depth.
+        let tokens: Vec<char> = (0..20).map(|i| char::from(b'a' + i)).collect();
+        let counts: HashMap<char, u64> =
+            tokens.iter().zip(0..).map(|(&c, i)| (c, 1 << i)).collect();
+        let encoder = HuffmanEncoder::from_frequencies(&counts).unwrap();
+        assert_eq!(encoder.codebook.max_length(), 20);
+
+        let encoder = EncoderBuilder::new()
+            .max_code_length(8)
+            .build(&counts)
+            .unwrap();
+        assert_eq!(encoder.codebook.max_length(), 8);
+        let bits = tokens.iter().fold(BitVec::new(), |mut bits, token| {
+            encoder.encode_token(token, &mut bits).unwrap();
+            bits
+        });
+        let decoder = HuffmanDecoder::from_codebook(encoder.codebook);
+        assert_eq!(decoder.decode_bits(&bits).unwrap(), tokens);
+    }
+
+    #[test]
+    fn test_builder_raises_limit_to_fit_every_token() {
+        let counts: HashMap<u32, u64> = (0..20).map(|i| (i, 1 << i)).collect();
+        let encoder = EncoderBuilder::new()
+            .max_code_length(2)
+            .build(&counts)
+            .unwrap();
+        // 20 tokens and the escape need codes of 5 bits.
+        assert_eq!(encoder.codebook.max_length(), 5);
+    }
+
+    #[test]
+    fn test_single_token_has_one_bit_code() {
+        let encoder = HuffmanEncoder::from_frequencies(&HashMap::from([('a', 4)])).unwrap();
+        assert_eq!(encoder.encoder[&'a'].len(), 1);
+
+        let encoded_text = encoder
+            .encode(&vec![String::from("aaaa")], |line| line.chars())
+            .unwrap();
+        assert_eq!(encoded_text[0].len(), 4);
+    }
+
+    #[test]
+    fn test_no_tokens_has_empty_codebook() {
+        let encoder = HuffmanEncoder::<char>::from_frequencies(&HashMap::new()).unwrap();
+        assert!(encoder.encoder.is_empty());
+        assert!(encoder.codebook.is_empty());
+        assert_eq!(encoder.codebook.escape, Some(1));
+    }
+
+    #[test]
+    fn test_unknown_tokens_are_escaped() {
+        let encoder =
+            HuffmanEncoder::from_frequencies(&HashMap::from([('a', 3), ('b', 1)])).unwrap();
+        let decoder = HuffmanDecoder::from_codebook(encoder.codebook.clone());
+
+        let input = vec![String::from("abc😆a\n")];
+        let encoded_text = encoder.encode(&input, |line| line.chars()).unwrap();
+        assert_eq!(
+            decoder
+                .decode(&encoded_text, |tokens: Vec<char>| tokens
+                    .into_iter()
+                    .collect::<String>()
+                    .into_bytes())
+                .unwrap(),
+            input[0].as_bytes()
         );
     }
+
+    #[test]
+    fn test_escape_count_shortens_escape_code() {
+        let counts = HashMap::from([('a', 8), ('b', 4), ('c', 2)]);
+        let encoder = HuffmanEncoder::from_frequencies(&counts).unwrap();
+        assert_eq!(encoder.codebook.escape, Some(3));
+
+        let encoder = EncoderBuilder::new()
+            .escape_count(16)
+            .build(&counts)
+            .unwrap();
+        assert_eq!(encoder.codebook.escape, Some(1));
+    }
+
+    #[test]
+    fn test_unknown_token_without_escape_is_an_error() {
+        let encoder = HuffmanEncoder::from_codebook(Codebook::from_code_lengths([('a', 1)]));
+        assert!(matches!(
+            encoder.encode_token(&'b', &mut BitVec::new()),
+            Err(Error::UnknownToken(literal)) if literal == b"b"
+        ));
+    }
 }
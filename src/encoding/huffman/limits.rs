@@ -0,0 +1,98 @@
+use crate::encoding::huffman::encoder::MAX_CODE_LENGTH;
+use std::io::{self, Read};
+
+/// Bounds on what the decoder accepts, so that crafted input cannot use unbounded memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Most symbols a codebook may hold, including the escape.
+    pub max_symbols: usize,
+    /// Longest code a codebook may hold, in bits. Cannot usefully be more than `MAX_CODE_LENGTH`.
+    pub max_code_length: u8,
+    /// Largest MessagePack body, in bytes. A framed body is limited one frame at a time.
+    pub max_body_size: u64,
+    /// Whether to check the decoded text against the checksums stored with it. Skipping the check
+    /// is a little faster, but text decoded wrongly from a damaged file is no longer detected.
+    pub verify_checksums: bool,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_symbols: 1 << 24,
+            max_code_length: MAX_CODE_LENGTH,
+            max_body_size: 1 << 32,
+            verify_checksums: true,
+        }
+    }
+}
+
+/// Reader which fails once more than `limit` bytes have been read, so a crafted length prefix
+/// cannot make the deserializer read an unbounded amount of data.
+pub(crate) struct LimitedReader<R> {
+    inner: R,
+    limit: u64,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R> LimitedReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        LimitedReader {
+            inner,
+            limit,
+            remaining: limit,
+            exceeded: false,
+        }
+    }
+
+    /// Allow another `limit` bytes to be read, e.g. for the next frame.
+    pub fn reset(&mut self) {
+        self.remaining = self.limit;
+        self.exceeded = false;
+    }
+
+    /// Whether a read failed because the limit was reached.
+    pub fn exceeded(&self) -> bool {
+        self.exceeded
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            self.exceeded = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "size limit exceeded",
+            ));
+        }
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..max])?;
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limited_reader_fails_past_limit() {
+        let mut reader = LimitedReader::new(&b"abcdef"[..], 4);
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"abcd");
+        assert!(reader.read(&mut buf).is_err());
+        assert!(reader.exceeded());
+
+        reader.reset();
+        assert_eq!(reader.read(&mut buf).unwrap(), 2);
+        assert!(!reader.exceeded());
+    }
+}
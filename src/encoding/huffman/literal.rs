@@ -0,0 +1,137 @@
+use bit_vec::BitVec;
+
+/// Tokens which can be written out in full, after the escape code, when they are missing from the
+/// codebook.
+pub trait Literal: Sized {
+    fn to_literal(&self) -> Vec<u8>;
+
+    /// Rebuild a token from its literal bytes, or `None` if they do not form a token.
+    fn from_literal(bytes: Vec<u8>) -> Option<Self>;
+}
+
+impl Literal for char {
+    fn to_literal(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+
+    fn from_literal(bytes: Vec<u8>) -> Option<Self> {
+        let text = String::from_utf8(bytes).ok()?;
+        let mut chars = text.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Some(c),
+            _ => None,
+        }
+    }
+}
+
+impl Literal for String {
+    fn to_literal(&self) -> Vec<u8> {
+        self.clone().into_bytes()
+    }
+
+    fn from_literal(bytes: Vec<u8>) -> Option<Self> {
+        String::from_utf8(bytes).ok()
+    }
+}
+
+impl Literal for u8 {
+    fn to_literal(&self) -> Vec<u8> {
+        vec![*self]
+    }
+
+    fn from_literal(bytes: Vec<u8>) -> Option<Self> {
+        match bytes[..] {
+            [byte] => Some(byte),
+            _ => None,
+        }
+    }
+}
+
+/// Append the literal form of a token: the number of bytes, as groups of 7 bits with a
+/// continuation bit, followed by the bytes themselves. Each group and byte is written most
+/// significant bit first.
+pub fn write_literal<T: Literal>(token: &T, bits: &mut BitVec) {
+    let bytes = token.to_literal();
+    let mut length = bytes.len();
+    loop {
+        let group = (length & 0x7f) as u8;
+        length >>= 7;
+        let more = if length > 0 { 0x80 } else { 0 };
+        write_byte(group | more, bits);
+        if length == 0 {
+            break;
+        }
+    }
+    for byte in bytes {
+        write_byte(byte, bits);
+    }
+}
+
+/// Read the literal starting at `position`, returning the token and the number of bits it took up.
+/// Returns `None` if the literal does not finish before `end`, or does not form a token.
+pub fn read_literal<T: Literal>(bits: &BitVec, position: usize, end: usize) -> Option<(T, usize)> {
+    let mut cursor = position;
+    let mut length: usize = 0;
+    let mut shift = 0;
+    loop {
+        let group = read_byte(bits, cursor, end)?;
+        cursor += 8;
+        length |= usize::from(group & 0x7f).checked_shl(shift)?;
+        if group & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    if length > (end - cursor) / 8 {
+        return None;
+    }
+
+    let bytes = (0..length)
+        .map(|i| read_byte(bits, cursor + 8 * i, end))
+        .collect::<Option<Vec<u8>>>()?;
+    let token = T::from_literal(bytes)?;
+    Some((token, cursor + 8 * length - position))
+}
+
+fn write_byte(byte: u8, bits: &mut BitVec) {
+    for i in (0..8).rev() {
+        bits.push(byte & (1 << i) != 0);
+    }
+}
+
+fn read_byte(bits: &BitVec, position: usize, end: usize) -> Option<u8> {
+    if end.checked_sub(position)? < 8 {
+        return None;
+    }
+    Some((position..position + 8).fold(0, |byte, i| (byte << 1) | u8::from(bits[i])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literals_round_trip() {
+        let mut bits = BitVec::new();
+        write_literal(&'😆', &mut bits);
+        write_literal(&String::from("x").repeat(200), &mut bits);
+        write_literal(&0xffu8, &mut bits);
+        // One length byte and four bytes, then two length bytes and 200 bytes.
+        assert_eq!(bits.len(), 8 * (1 + 4) + 8 * (2 + 200) + 8 * (1 + 1));
+
+        let (c, c_len) = read_literal::<char>(&bits, 0, bits.len()).unwrap();
+        assert_eq!((c, c_len), ('😆', 40));
+        let (s, s_len) = read_literal::<String>(&bits, c_len, bits.len()).unwrap();
+        assert_eq!(s, "x".repeat(200));
+        let (b, _) = read_literal::<u8>(&bits, c_len + s_len, bits.len()).unwrap();
+        assert_eq!(b, 0xff);
+    }
+
+    #[test]
+    fn test_truncated_literal_is_rejected() {
+        let mut bits = BitVec::new();
+        write_literal(&String::from("hello"), &mut bits);
+        assert!(read_literal::<String>(&bits, 0, bits.len() - 1).is_none());
+        assert!(read_literal::<char>(&bits, 0, bits.len()).is_none());
+    }
+}
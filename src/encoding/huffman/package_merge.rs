@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+/// Smallest code length limit which leaves room for a code for each of `symbol_count` symbols.
+pub fn min_code_length(symbol_count: usize) -> u8 {
+    let mut length = 1;
+    while length < 64 && (1_u64 << length) < symbol_count as u64 {
+        length += 1;
+    }
+    length
+}
+
+/// Find optimal code lengths of at most `max_code_length` bits, using the package-merge algorithm.
+///
+/// Every symbol starts out as a coin at each of the `max_code_length` levels, worth its count.
+/// Working up from the deepest level, the coins are paired into packages, which are merged into
+/// the coins of the level above. The cheapest `2n - 2` items at the top level are then the
+/// cheapest set of coins which pay for a complete prefix code, and the code length of each symbol
+/// is the number of times one of its coins was chosen.
+///
+/// `max_code_length` must leave room for every symbol, see `min_code_length`.
+pub fn code_lengths<T: Clone + Ord>(
+    frequencies: &HashMap<T, u64>,
+    max_code_length: u8,
+) -> Vec<(T, u8)> {
+    // A package holds at most one coin of each symbol from each level below it, so its weight is
+    // at most `max_code_length` times the total count, which fits in a `u128`.
+    let mut symbols: Vec<(&T, u128)> = frequencies
+        .iter()
+        .map(|(symbol, &count)| (symbol, u128::from(count)))
+        .collect();
+    symbols.sort_unstable_by(|(a, a_count), (b, b_count)| a_count.cmp(b_count).then(a.cmp(b)));
+
+    let n = symbols.len();
+    if n < 2 {
+        return symbols
+            .into_iter()
+            .map(|(symbol, _)| (symbol.clone(), 1))
+            .collect();
+    }
+    assert!(
+        max_code_length >= min_code_length(n),
+        "{n} symbols do not fit in codes of {max_code_length} bits"
+    );
+
+    // For each level above the deepest, whether each item in the merged list is a coin (true) or
+    // a package (false). The coins in a list are always the cheapest symbols, in order.
+    let mut levels: Vec<Vec<bool>> = Vec::with_capacity(usize::from(max_code_length) - 1);
+    let mut items: Vec<u128> = symbols.iter().map(|&(_, count)| count).collect();
+    for _ in 1..max_code_length {
+        let packages = items.chunks_exact(2).map(|pair| pair[0] + pair[1]);
+        let mut merged = Vec::with_capacity(n + items.len() / 2);
+        let mut is_coin = Vec::with_capacity(n + items.len() / 2);
+        let mut coins = symbols.iter().map(|&(_, count)| count).peekable();
+        for package in packages {
+            while let Some(coin) = coins.next_if(|&coin| coin <= package) {
+                merged.push(coin);
+                is_coin.push(true);
+            }
+            merged.push(package);
+            is_coin.push(false);
+        }
+        for coin in coins {
+            merged.push(coin);
+            is_coin.push(true);
+        }
+        items = merged;
+        levels.push(is_coin);
+    }
+
+    // Walk back down from the top level, unpacking the chosen packages into the level below.
+    let mut lengths = vec![0_u8; n];
+    let mut chosen = 2 * n - 2;
+    for is_coin in levels.iter().rev() {
+        let coins = is_coin[..chosen].iter().filter(|&&coin| coin).count();
+        for length in &mut lengths[..coins] {
+            *length += 1;
+        }
+        chosen = 2 * (chosen - coins);
+    }
+    // The deepest level only holds coins.
+    for length in &mut lengths[..chosen] {
+        *length += 1;
+    }
+
+    symbols
+        .into_iter()
+        .zip(lengths)
+        .map(|((symbol, _), length)| (symbol.clone(), length))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(mut code_lengths: Vec<(char, u8)>) -> Vec<(char, u8)> {
+        code_lengths.sort();
+        code_lengths
+    }
+
+    #[test]
+    fn test_matches_huffman_when_limit_is_not_reached() {
+        let frequencies = HashMap::from([('a', 1), ('b', 1), ('c', 2), ('d', 4), ('e', 8)]);
+        assert_eq!(
+            sorted(code_lengths(&frequencies, 8)),
+            vec![('a', 4), ('b', 4), ('c', 3), ('d', 2), ('e', 1)]
+        );
+    }
+
+    #[test]
+    fn test_limits_code_length() {
+        let frequencies = HashMap::from([('a', 1), ('b', 1), ('c', 2), ('d', 4), ('e', 8)]);
+        assert_eq!(
+            sorted(code_lengths(&frequencies, 3)),
+            vec![('a', 3), ('b', 3), ('c', 3), ('d', 3), ('e', 1)]
+        );
+    }
+
+    #[test]
+    fn test_lengths_form_a_complete_code() {
+        // Fibonacci counts give the deepest possible Huffman tree.
+        let mut fibonacci = vec![1, 1];
+        while fibonacci.len() < 30 {
+            fibonacci.push(fibonacci[fibonacci.len() - 1] + fibonacci[fibonacci.len() - 2]);
+        }
+        let frequencies: HashMap<u32, u64> = (0..).zip(fibonacci).collect();
+        for max_code_length in [5, 8, 12, 29] {
+            let code_lengths = code_lengths(&frequencies, max_code_length);
+            assert!(code_lengths
+                .iter()
+                .all(|&(_, length)| (1..=max_code_length).contains(&length)));
+            let kraft: f64 = code_lengths
+                .iter()
+                .map(|&(_, length)| 0.5_f64.powi(i32::from(length)))
+                .sum();
+            assert_eq!(kraft, 1.0);
+        }
+    }
+
+    #[test]
+    fn test_min_code_length() {
+        assert_eq!(min_code_length(0), 1);
+        assert_eq!(min_code_length(2), 1);
+        assert_eq!(min_code_length(3), 2);
+        assert_eq!(min_code_length(256), 8);
+        assert_eq!(min_code_length(257), 9);
+    }
+}
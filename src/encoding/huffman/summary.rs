@@ -0,0 +1,213 @@
+use crate::{
+    encoding::huffman::{
+        check_end, deserialize_limited, limits::LimitedReader, read_compressed, Codebook,
+        CompressedData, DecodeLimits, Frame, Payload,
+    },
+    Error,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fmt::{self, Display},
+    io::Read,
+};
+
+/// How the encoded text of a body is laid out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    /// A single `CompressedData` holding a bit stream.
+    Stream,
+    /// A single `CompressedData` holding one bit vector per line.
+    Lines,
+    /// A sequence of frames, written one block at a time.
+    Framed,
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layout::Stream => write!(f, "stream"),
+            Layout::Lines => write!(f, "lines"),
+            Layout::Framed => write!(f, "framed"),
+        }
+    }
+}
+
+/// Size and code lengths of a codebook.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CodebookSummary {
+    /// Number of symbols, including the escape.
+    pub symbols: usize,
+    /// Size of the codebook as stored, in bytes.
+    pub bytes: usize,
+    pub max_length: u8,
+    /// Mean code length over the symbols, not weighted by how often each one occurs.
+    pub mean_length: f64,
+}
+
+impl CodebookSummary {
+    pub fn of<T: Serialize>(codebook: &Codebook<T>) -> Result<Self, Error> {
+        let lengths: Vec<u8> = codebook.entries().map(|(_, length)| length).collect();
+        let total: u64 = lengths.iter().map(|&length| u64::from(length)).sum();
+        Ok(CodebookSummary {
+            symbols: lengths.len(),
+            bytes: rmp_serde::encode::to_vec(codebook)?.len(),
+            max_length: codebook.max_length(),
+            mean_length: match lengths.len() {
+                0 => 0.0,
+                symbols => total as f64 / symbols as f64,
+            },
+        })
+    }
+}
+
+/// What a compressed body holds, read from its structure without decoding any text.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BodySummary {
+    pub layout: Layout,
+    /// Number of independently decodable blocks. Zero for the `Lines` layout, whose lines are
+    /// decoded independently instead.
+    pub blocks: usize,
+    /// Number of lines, which only the `Lines` layout records.
+    pub lines: Option<usize>,
+    /// Total length of the encoded text, in bits.
+    pub bits: u64,
+    /// Codebooks stored in the body, in order. Empty if it was compressed with a shared codebook
+    /// or a built-in model.
+    pub codebooks: Vec<CodebookSummary>,
+    /// Length of the original input, if the body records it. Only a framed body does, at its end.
+    pub original_length: Option<u64>,
+    /// CRC32 of the whole original input.
+    pub checksum: u32,
+    /// Number of blocks with a checksum of their own.
+    pub block_checksums: usize,
+}
+
+impl<T: Serialize> CompressedData<T> {
+    pub fn summary(&self) -> Result<BodySummary, Error> {
+        let (layout, blocks, lines, bits, block_checksums) = match &self.data {
+            Payload::Stream(stream) => (
+                Layout::Stream,
+                stream.block_ends.len(),
+                None,
+                stream.bits.len() as u64,
+                stream.block_checksums.len(),
+            ),
+            Payload::Lines(lines) => (
+                Layout::Lines,
+                0,
+                Some(lines.len()),
+                lines.iter().map(|bits| bits.len() as u64).sum(),
+                0,
+            ),
+        };
+        Ok(BodySummary {
+            layout,
+            blocks,
+            lines,
+            bits,
+            codebooks: self
+                .codebook
+                .iter()
+                .map(CodebookSummary::of)
+                .collect::<Result<_, _>>()?,
+            original_length: None,
+            checksum: self.checksum,
+            block_checksums,
+        })
+    }
+}
+
+/// Read a body and summarize it, without decoding it. `framed` says whether the body is a sequence
+/// of frames, as recorded by `container::FLAG_FRAMED`.
+pub fn summarize<T, R>(reader: R, framed: bool, limits: &DecodeLimits) -> Result<BodySummary, Error>
+where
+    T: Serialize + DeserializeOwned,
+    R: Read,
+{
+    if !framed {
+        return read_compressed::<T, R>(reader, limits)?.summary();
+    }
+
+    let mut deserializer =
+        rmp_serde::Deserializer::new(LimitedReader::new(reader, limits.max_body_size));
+    let mut summary = BodySummary {
+        layout: Layout::Framed,
+        blocks: 0,
+        lines: None,
+        bits: 0,
+        codebooks: Vec::new(),
+        original_length: None,
+        checksum: 0,
+        block_checksums: 0,
+    };
+    loop {
+        match deserialize_limited(&mut deserializer, limits)? {
+            Frame::<T>::Block { codebook, bits, .. } => {
+                if let Some(codebook) = codebook {
+                    summary.codebooks.push(CodebookSummary::of(&codebook)?);
+                }
+                summary.blocks += 1;
+                summary.block_checksums += 1;
+                summary.bits += bits.len() as u64;
+            }
+            Frame::End {
+                original_length,
+                checksum,
+            } => {
+                check_end(deserializer.get_mut())?;
+                summary.original_length = Some(original_length);
+                summary.checksum = checksum;
+                return Ok(summary);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        application::parser::{read_lines, TokenParser},
+        encoding::huffman::{compress, compress_framed, EncoderBuilder},
+    };
+
+    #[test]
+    fn test_summaries_match_the_compressed_data() {
+        let text = "It was the best of times,\nit was the worst of times,\n".repeat(3000);
+        let input = TokenParser::chars_from_reader(text.as_bytes()).unwrap();
+        let compressed = compress(
+            &input.lines,
+            input.token_frequencies.clone(),
+            &EncoderBuilder::new(),
+            |line| line.chars(),
+        )
+        .unwrap();
+        let body = rmp_serde::encode::to_vec(&compressed).unwrap();
+        let summary = summarize::<char, _>(&body[..], false, &DecodeLimits::default()).unwrap();
+        assert_eq!(summary, compressed.summary().unwrap());
+        assert_eq!(summary.layout, Layout::Stream);
+        assert!(summary.blocks > 1);
+        assert_eq!(summary.block_checksums, summary.blocks);
+        // The tokens are the distinct chars, plus the escape.
+        assert_eq!(summary.codebooks.len(), 1);
+        assert_eq!(
+            summary.codebooks[0].symbols,
+            input.token_frequencies.len() + 1
+        );
+
+        let mut framed = Vec::new();
+        compress_framed(
+            read_lines(text.as_bytes()),
+            input.token_frequencies,
+            &EncoderBuilder::new(),
+            &mut framed,
+            |line| line.chars().collect(),
+        )
+        .unwrap();
+        let summary = summarize::<char, _>(&framed[..], true, &DecodeLimits::default()).unwrap();
+        assert_eq!(summary.layout, Layout::Framed);
+        assert_eq!(summary.original_length, Some(text.len() as u64));
+        assert_eq!(summary.checksum, crc32fast::hash(text.as_bytes()));
+        assert_eq!(summary.codebooks.len(), 1);
+    }
+}
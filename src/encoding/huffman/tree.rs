@@ -1,4 +1,6 @@
+use crate::Error;
 use std::{
+    cmp::Ordering,
     collections::{BinaryHeap, HashMap},
     hash::Hash,
 };
@@ -8,55 +10,129 @@ use std::{
 pub enum HuffmanTree<T> {
     Leaf {
         token: T,
-        count: u32,
+        count: u64,
     },
     InternalNode {
-        count: u32,
+        count: u64,
         left: Box<HuffmanTree<T>>,
         right: Box<HuffmanTree<T>>,
     },
 }
 
-impl<T: Clone + Eq> Ord for HuffmanTree<T> {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        other.get_count().cmp(&self.get_count())
+/// A subtree waiting in the priority queue while the tree is being built.
+///
+/// The queue pops the node with the lowest count first. Ties are broken by rank: leaves are ranked
+/// by the order of their tokens, and internal nodes rank after every leaf, in the order they were
+/// created. This makes the shape of the tree, and so the code lengths, depend only on the
+/// frequencies, and not on the iteration order of the map they are stored in.
+struct QueuedNode<T> {
+    count: u64,
+    rank: usize,
+    node: Box<HuffmanTree<T>>,
+}
+
+impl<T> QueuedNode<T> {
+    fn new(node: Box<HuffmanTree<T>>, rank: usize) -> Self {
+        QueuedNode {
+            count: node.get_count(),
+            rank,
+            node,
+        }
+    }
+}
+
+impl<T> PartialEq for QueuedNode<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T> Eq for QueuedNode<T> {}
+
+impl<T> Ord for QueuedNode<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.count, other.rank).cmp(&(self.count, self.rank))
     }
 }
 
-impl<T: Clone + Eq> PartialOrd for HuffmanTree<T> {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+impl<T> PartialOrd for QueuedNode<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl<T: Clone + Eq> HuffmanTree<T> {
+impl<T: Clone + Ord> HuffmanTree<T> {
     /// Build a Huffman Tree, given the frequency of tokens in a given text.
-    pub fn from_frequencies(counts: &HashMap<T, u32>) -> Box<HuffmanTree<T>> {
-        let mut heap: BinaryHeap<Box<HuffmanTree<T>>> = counts
-            .iter()
-            .map(|(key, value)| {
-                Box::new(HuffmanTree::Leaf {
+    ///
+    /// Nodes with equal counts are merged in a fixed order (count, then token order, then the
+    /// order in which internal nodes were created), so the same frequencies always give the same
+    /// tree.
+    ///
+    /// Returns `None` if there are no tokens. If there is a single distinct token, the tree is just
+    /// a leaf. Fails if the total count of tokens does not fit in a `u64`.
+    pub fn from_frequencies(
+        counts: &HashMap<T, u64>,
+    ) -> Result<Option<Box<HuffmanTree<T>>>, Error> {
+        let mut leaves: Vec<(&T, &u64)> = counts.iter().collect();
+        leaves.sort_unstable_by_key(|(token, _)| *token);
+
+        let mut heap: BinaryHeap<QueuedNode<T>> = leaves
+            .into_iter()
+            .enumerate()
+            .map(|(rank, (key, value))| {
+                let leaf = HuffmanTree::Leaf {
                     token: key.clone(),
                     count: *value,
-                })
+                };
+                QueuedNode::new(Box::new(leaf), rank)
             })
             .collect();
 
+        let mut next_rank = heap.len();
         while heap.len() > 1 {
             let smaller_node = heap.pop().unwrap();
             let larger_node = heap.pop().unwrap();
             let parent_node = Box::new(HuffmanTree::InternalNode {
-                count: (*smaller_node).get_count() + (*larger_node).get_count(),
-                left: smaller_node,
-                right: larger_node,
+                count: smaller_node
+                    .count
+                    .checked_add(larger_node.count)
+                    .ok_or(Error::CountOverflow)?,
+                left: smaller_node.node,
+                right: larger_node.node,
             });
-            heap.push(parent_node);
+            heap.push(QueuedNode::new(parent_node, next_rank));
+            next_rank += 1;
         }
 
-        heap.pop().unwrap()
+        Ok(heap.pop().map(|root| root.node))
+    }
+}
+
+impl<T> HuffmanTree<T> {
+    /// The depth of each leaf, which is the length of its token's code.
+    ///
+    /// A tree which is a single leaf still gives its token a code of one bit, otherwise the number
+    /// of times it occurs could not be recovered from the encoded text.
+    pub fn code_lengths(self) -> Vec<(T, u8)> {
+        let mut code_lengths = Vec::new();
+        match self {
+            HuffmanTree::Leaf { token, .. } => code_lengths.push((token, 1)),
+            root => root.collect_code_lengths(0, &mut code_lengths),
+        }
+        code_lengths
+    }
+
+    fn collect_code_lengths(self, depth: u8, code_lengths: &mut Vec<(T, u8)>) {
+        match self {
+            HuffmanTree::Leaf { token, .. } => code_lengths.push((token, depth)),
+            HuffmanTree::InternalNode { left, right, .. } => {
+                left.collect_code_lengths(depth + 1, code_lengths);
+                right.collect_code_lengths(depth + 1, code_lengths);
+            }
+        }
     }
 
-    fn get_count(&self) -> u32 {
+    fn get_count(&self) -> u64 {
         match self {
             HuffmanTree::Leaf { count, .. } => *count,
             HuffmanTree::InternalNode { count, .. } => *count,
@@ -76,8 +152,8 @@ fn test_from_frequencies() {
         map
     });
 
-    let tree = HuffmanTree::from_frequencies(&counts);
-    assert_eq!(tree.get_count(), u32::try_from(lyrics.len()).unwrap())
+    let tree = HuffmanTree::from_frequencies(&counts).unwrap().unwrap();
+    assert_eq!(tree.get_count(), lyrics.len() as u64)
 }
 
 #[test]
@@ -92,11 +168,11 @@ fn test_char_leaves_have_correct_count() {
         map
     });
 
-    let tree = HuffmanTree::from_frequencies(&counts);
+    let tree = HuffmanTree::from_frequencies(&counts).unwrap().unwrap();
 
-    assert_eq!(tree.get_count(), u32::try_from(lyrics.len()).unwrap());
+    assert_eq!(tree.get_count(), lyrics.len() as u64);
 
-    check_leaves(tree, counts);
+    check_leaves(*tree, counts);
 }
 
 #[test]
@@ -113,25 +189,79 @@ My name is, chka-chka, Slim Shady";
             map
         });
 
-    let tree = HuffmanTree::from_frequencies(&counts);
+    let tree = HuffmanTree::from_frequencies(&counts).unwrap().unwrap();
 
     assert_eq!(tree.get_count(), 27);
 
-    check_leaves(tree, counts);
+    check_leaves(*tree, counts);
+}
+
+#[test]
+fn test_no_tokens_has_no_tree() {
+    let counts: HashMap<char, u64> = HashMap::new();
+    assert!(matches!(HuffmanTree::from_frequencies(&counts), Ok(None)));
+}
+
+#[test]
+fn test_single_token_is_a_leaf() {
+    let counts = HashMap::from([('a', 4)]);
+    let tree = HuffmanTree::from_frequencies(&counts).unwrap().unwrap();
+    assert!(
+        *tree
+            == HuffmanTree::Leaf {
+                token: 'a',
+                count: 4
+            }
+    );
+}
+
+#[test]
+fn test_ties_are_broken_by_token_order() {
+    let expected = HuffmanTree::from_frequencies(&HashMap::from([
+        ('a', 1),
+        ('b', 1),
+        ('c', 1),
+        ('d', 1),
+        ('e', 2),
+    ]))
+    .unwrap()
+    .unwrap();
+
+    // Each map has its own random hasher, so is iterated in a different order.
+    for _ in 0..20 {
+        let counts: HashMap<char, u64> = "abcdee".chars().fold(HashMap::new(), |mut map, c| {
+            *map.entry(c).or_insert(0) += 1;
+            map
+        });
+        assert!(HuffmanTree::from_frequencies(&counts).unwrap().unwrap() == expected);
+    }
+}
+
+#[test]
+fn test_count_overflow_is_an_error() {
+    let counts = HashMap::from([('a', u64::MAX), ('b', 1)]);
+    assert!(matches!(
+        HuffmanTree::from_frequencies(&counts),
+        Err(Error::CountOverflow)
+    ));
+
+    let counts = HashMap::from([('a', u64::MAX - 1), ('b', 1)]);
+    let tree = HuffmanTree::from_frequencies(&counts).unwrap().unwrap();
+    assert_eq!(tree.get_count(), u64::MAX);
 }
 
 #[allow(dead_code)]
-fn check_leaves<T>(tree: Box<HuffmanTree<T>>, counts: HashMap<T, u32>)
+fn check_leaves<T>(tree: HuffmanTree<T>, counts: HashMap<T, u64>)
 where
     T: Clone + Eq + Hash,
 {
-    match *tree {
+    match tree {
         HuffmanTree::Leaf { count, token } => {
             assert_eq!(count, *counts.get(&token).unwrap())
         }
         HuffmanTree::InternalNode { left, right, .. } => {
-            check_leaves(left, counts.clone());
-            check_leaves(right, counts.clone());
+            check_leaves(*left, counts.clone());
+            check_leaves(*right, counts.clone());
         }
     }
 }
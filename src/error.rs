@@ -0,0 +1,170 @@
+use crate::{application::parser::ParseError, container::FormatError};
+use std::{
+    fmt::{self, Display},
+    io,
+};
+
+/// Reasons the compressor library can fail.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading or writing failed.
+    Io(io::Error),
+    /// The input could not be read as the requested token type, e.g. text which is not UTF-8.
+    Parse(ParseError),
+    /// The data is not laid out as the compressor writes it, e.g. a missing header or a malformed
+    /// MessagePack body.
+    InvalidFormat(String),
+    /// The data was written with a version of the format which this build cannot read.
+    UnsupportedVersion(u8),
+    /// The data is laid out correctly, but does not decode to valid text.
+    CorruptData(String),
+    /// The literal form of a token which is missing from a codebook without an escape code, so
+    /// cannot be encoded.
+    UnknownToken(Vec<u8>),
+    /// The checksum stored with some data does not match the data. `part` names the part of the
+    /// data with its own checksum, e.g. "block 3", or is `None` for the whole of it.
+    ChecksumMismatch {
+        part: Option<String>,
+        expected: u32,
+        actual: u32,
+    },
+    /// The total count of tokens is too large to be stored.
+    CountOverflow,
+}
+
+impl Error {
+    /// Name the part of the data a `CorruptData` or `ChecksumMismatch` error was found in, e.g.
+    /// "block 3".
+    pub(crate) fn in_part(self, part: impl Display) -> Self {
+        match self {
+            Error::CorruptData(reason) => Error::CorruptData(format!("{part}: {reason}")),
+            Error::ChecksumMismatch {
+                part: inner,
+                expected,
+                actual,
+            } => Error::ChecksumMismatch {
+                part: Some(match inner {
+                    Some(inner) => format!("{part}: {inner}"),
+                    None => part.to_string(),
+                }),
+                expected,
+                actual,
+            },
+            e => e,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{e}"),
+            Error::Parse(e) => write!(f, "{e}"),
+            Error::InvalidFormat(reason) => write!(f, "invalid format: {reason}"),
+            Error::UnsupportedVersion(v) => write!(f, "unsupported format version {v}"),
+            Error::CorruptData(reason) => write!(f, "corrupt data: {reason}"),
+            Error::UnknownToken(literal) => write!(
+                f,
+                "token {:?} is missing from a codebook without an escape code",
+                String::from_utf8_lossy(literal)
+            ),
+            Error::ChecksumMismatch {
+                part,
+                expected,
+                actual,
+            } => {
+                if let Some(part) = part {
+                    write!(f, "{part}: ")?;
+                }
+                write!(
+                    f,
+                    "checksum mismatch: expected {expected:08x}, found {actual:08x}"
+                )
+            }
+            Error::CountOverflow => write!(f, "token counts add up to more than {}", u64::MAX),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Parse(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(error: ParseError) -> Self {
+        Error::Parse(error)
+    }
+}
+
+impl From<FormatError> for Error {
+    fn from(error: FormatError) -> Self {
+        match error {
+            FormatError::UnsupportedVersion { version, .. } => Error::UnsupportedVersion(version),
+            FormatError::Io(e) => Error::Io(e),
+            e => Error::InvalidFormat(e.to_string()),
+        }
+    }
+}
+
+impl From<rmp_serde::decode::Error> for Error {
+    fn from(error: rmp_serde::decode::Error) -> Self {
+        use rmp_serde::decode::Error::{InvalidDataRead, InvalidMarkerRead};
+        match error {
+            // Running out of data is a malformed body, anything else is a failure to read it.
+            InvalidMarkerRead(e) | InvalidDataRead(e)
+                if e.kind() != io::ErrorKind::UnexpectedEof =>
+            {
+                Error::Io(e)
+            }
+            e => Error::InvalidFormat(e.to_string()),
+        }
+    }
+}
+
+impl From<rmp_serde::encode::Error> for Error {
+    fn from(error: rmp_serde::encode::Error) -> Self {
+        Error::Io(io::Error::other(error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_errors_keep_their_kind() {
+        assert!(matches!(
+            Error::from(FormatError::UnsupportedVersion {
+                version: 9,
+                supported: 6
+            }),
+            Error::UnsupportedVersion(9)
+        ));
+        assert!(matches!(
+            Error::from(FormatError::NotCompressorFile),
+            Error::InvalidFormat(_)
+        ));
+    }
+
+    #[test]
+    fn test_truncated_body_is_invalid_format() {
+        let bytes = rmp_serde::encode::to_vec(&("abc", 1)).unwrap();
+        let truncated: Result<(String, u8), _> = rmp_serde::decode::from_slice(&bytes[..3]);
+        assert!(matches!(
+            Error::from(truncated.unwrap_err()),
+            Error::InvalidFormat(_)
+        ));
+    }
+}
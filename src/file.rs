@@ -0,0 +1,732 @@
+use crate::{
+    application::{
+        cli::{CompressOptions, FileArgs, PayloadType, TokenType},
+        parser::{self, TokenParser},
+    },
+    container::{self, CodebookHeader, FormatError, Header, FLAG_FRAMED, FLAG_UNKNOWN_LENGTH},
+    encoding::huffman::{
+        self, Codebook, CompressedData, DecodeLimits, EncoderBuilder, HuffmanEncoder, Literal,
+    },
+    models::Model,
+    Error,
+};
+use log::info;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs,
+    hash::Hash,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write},
+    path::Path,
+};
+
+/// Compress a file, or stdin if there is none, as the options ask, writing a whole compressed
+/// file, header and all, to `output`.
+pub fn compress(
+    options: &CompressOptions,
+    input_file: Option<&Path>,
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let builder = EncoderBuilder::new().max_code_length(options.max_code_length);
+    if let Some(model) = options.model {
+        return compress_with_codebook(options, model.codebook_file(), input_file, output);
+    }
+    if let Some(codebook_file) = &options.codebook {
+        info!("Reading codebook {codebook_file}...");
+        let codebook = BufReader::new(fs::File::open(codebook_file)?);
+        return compress_with_codebook(options, codebook, input_file, output);
+    }
+    if options.streaming {
+        return match input_file {
+            Some(input_file) => {
+                compress_streaming(options.token_type, input_file, &builder, output)
+            }
+            None => compress_blocks(options.token_type, options.block_size, &builder, output),
+        };
+    }
+    let data = compress_in_memory(options.token_type, options.payload, input_file, &builder)?;
+    output.write_all(&data)?;
+    Ok(())
+}
+
+fn compress_in_memory(
+    token_type: TokenType,
+    payload: PayloadType,
+    input_file: Option<&Path>,
+    builder: &EncoderBuilder,
+) -> Result<Vec<u8>, Error> {
+    info!("Compressing text...");
+    match token_type {
+        TokenType::Chars => {
+            info!("Generating char tokens...");
+            let input_data = match input_file {
+                Some(s) => TokenParser::chars_from_reader(BufReader::new(fs::File::open(s)?))?,
+                None => TokenParser::chars_from_reader(io::stdin().lock())?,
+            };
+
+            info!("Performing Huffman Compression...");
+            let compressed = match payload {
+                PayloadType::Stream => huffman::compress(
+                    &input_data.lines,
+                    input_data.token_frequencies,
+                    builder,
+                    |line| line.chars(),
+                ),
+                PayloadType::Lines => huffman::compress_lines(
+                    &input_data.lines,
+                    input_data.token_frequencies,
+                    builder,
+                    |line| line.chars(),
+                ),
+            }?;
+
+            info!("Encoding into MessagePack format...");
+            let header = Header::new(token_type, original_length(&input_data.lines));
+            write_container(&header, &compressed)
+        }
+        TokenType::Words => {
+            info!("Generating word tokens...");
+            let input_data = match input_file {
+                Some(s) => TokenParser::words_from_reader(BufReader::new(fs::File::open(s)?))?,
+                None => TokenParser::words_from_reader(io::stdin().lock())?,
+            };
+
+            info!("Performing Huffman Compression...");
+            let compressed = match payload {
+                PayloadType::Stream => huffman::compress(
+                    &input_data.lines,
+                    input_data.token_frequencies,
+                    builder,
+                    |line| line.split_inclusive(' ').map(|token| token.to_string()),
+                ),
+                PayloadType::Lines => huffman::compress_lines(
+                    &input_data.lines,
+                    input_data.token_frequencies,
+                    builder,
+                    |line| line.split_inclusive(' ').map(|token| token.to_string()),
+                ),
+            }?;
+
+            info!("Encoding into MessagePack...");
+            let header = Header::new(token_type, original_length(&input_data.lines));
+            write_container(&header, &compressed)
+        }
+        TokenType::Bytes => {
+            info!("Generating byte tokens...");
+            let input_data = match input_file {
+                Some(s) => TokenParser::bytes_from_reader(BufReader::new(fs::File::open(s)?))?,
+                None => TokenParser::bytes_from_reader(io::stdin().lock())?,
+            };
+
+            info!("Performing Huffman Compression...");
+            let compressed = match payload {
+                PayloadType::Stream => huffman::compress(
+                    &input_data.lines,
+                    input_data.token_frequencies,
+                    builder,
+                    |chunk| chunk.iter().copied(),
+                ),
+                PayloadType::Lines => huffman::compress_lines(
+                    &input_data.lines,
+                    input_data.token_frequencies,
+                    builder,
+                    |chunk| chunk.iter().copied(),
+                ),
+            }?;
+
+            info!("Encoding into MessagePack...");
+            let header = Header::new(token_type, original_length(&input_data.lines));
+            write_container(&header, &compressed)
+        }
+    }
+}
+
+fn original_length<L: AsRef<[u8]>>(lines: &[L]) -> u64 {
+    lines.iter().map(|line| line.as_ref().len() as u64).sum()
+}
+
+fn write_container<T: Serialize>(
+    header: &Header,
+    compressed: &CompressedData<T>,
+) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::new();
+    header.write_to(&mut out)?;
+    rmp_serde::encode::write(&mut out, compressed)?;
+    Ok(out)
+}
+
+/// Compress with a shared codebook or a built-in model, which is left out of the output. `codebook`
+/// reads it in the format of a codebook file.
+pub fn compress_with_codebook(
+    options: &CompressOptions,
+    mut codebook: impl Read,
+    input_file: Option<&Path>,
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let token_type = CodebookHeader::read_from(&mut codebook)?.token_type;
+
+    let input: Box<dyn BufRead> = match input_file {
+        Some(s) => Box::new(BufReader::new(fs::File::open(s)?)),
+        None => Box::new(io::stdin().lock()),
+    };
+    match token_type {
+        TokenType::Chars => compress_shared(
+            Codebook::read_from(codebook, &DecodeLimits::default())?,
+            token_type,
+            options,
+            parser::read_lines(input),
+            |line: &String| line.chars().collect(),
+            output,
+        ),
+        TokenType::Words => compress_shared(
+            Codebook::read_from(codebook, &DecodeLimits::default())?,
+            token_type,
+            options,
+            parser::read_lines(input),
+            |line: &String| line.split_inclusive(' ').map(String::from).collect(),
+            output,
+        ),
+        TokenType::Bytes => compress_shared(
+            Codebook::read_from(codebook, &DecodeLimits::default())?,
+            token_type,
+            options,
+            parser::read_chunks(input),
+            |chunk: &Vec<u8>| chunk.clone(),
+            output,
+        ),
+    }
+}
+
+fn compress_shared<T, L>(
+    codebook: Codebook<T>,
+    token_type: TokenType,
+    options: &CompressOptions,
+    lines: impl Iterator<Item = Result<L, Error>>,
+    get_tokens_from_line: impl Fn(&L) -> Vec<T> + Send + Sync,
+    output: &mut impl Write,
+) -> Result<(), Error>
+where
+    T: Hash + Eq + Ord + Clone + Send + Sync + Serialize + Literal,
+    L: AsRef<[u8]> + Sync,
+{
+    let header = match options.model {
+        Some(model) => {
+            info!("Using built-in model {model:?}");
+            Header::new(token_type, 0).with_model_id(model.id())
+        }
+        None => {
+            let codebook_id = container::codebook_id(&codebook);
+            info!("Using shared codebook {codebook_id:016x}");
+            Header::new(token_type, 0).with_codebook_id(codebook_id)
+        }
+    };
+    let encoder = HuffmanEncoder::from_codebook(codebook);
+
+    if options.streaming {
+        info!("Compressing in blocks...");
+        let mut header = header;
+        header.flags |= FLAG_FRAMED | FLAG_UNKNOWN_LENGTH;
+        header.write_to(output)?;
+        return huffman::encode_framed(lines, &encoder, false, output, get_tokens_from_line);
+    }
+
+    info!("Compressing...");
+    let lines = lines.collect::<Result<Vec<L>, _>>()?;
+    let get_tokens = |line| get_tokens_from_line(line).into_iter();
+    let compressed: CompressedData<T> = CompressedData {
+        data: match options.payload {
+            PayloadType::Stream => huffman::encode_stream(&lines, &encoder, get_tokens)?,
+            PayloadType::Lines => huffman::encode_lines(&lines, &encoder, get_tokens)?,
+        },
+        codebook: None,
+        checksum: huffman::checksum::of_lines(&lines),
+    };
+    let header = Header {
+        original_length: original_length(&lines),
+        ..header
+    };
+    output.write_all(&write_container(&header, &compressed)?)?;
+    Ok(())
+}
+
+fn compress_streaming(
+    token_type: TokenType,
+    input_file: &Path,
+    builder: &EncoderBuilder,
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    info!("Compressing text in two passes...");
+    let mut reader = BufReader::new(fs::File::open(input_file)?);
+    match token_type {
+        TokenType::Chars => {
+            info!("Counting char tokens...");
+            let frequencies = TokenParser::char_frequencies(&mut reader)?;
+            write_framed_header(token_type, &mut reader, output)?;
+
+            info!("Performing Huffman Compression...");
+            huffman::compress_framed(
+                parser::read_lines(reader),
+                frequencies,
+                builder,
+                output,
+                |line| line.chars().collect(),
+            )
+        }
+        TokenType::Words => {
+            info!("Counting word tokens...");
+            let frequencies = TokenParser::word_frequencies(&mut reader)?;
+            write_framed_header(token_type, &mut reader, output)?;
+
+            info!("Performing Huffman Compression...");
+            huffman::compress_framed(
+                parser::read_lines(reader),
+                frequencies,
+                builder,
+                output,
+                |line| line.split_inclusive(' ').map(String::from).collect(),
+            )
+        }
+        TokenType::Bytes => {
+            info!("Counting byte tokens...");
+            let frequencies = TokenParser::byte_frequencies(&mut reader)?;
+            write_framed_header(token_type, &mut reader, output)?;
+
+            info!("Performing Huffman Compression...");
+            huffman::compress_framed(
+                parser::read_chunks(reader),
+                frequencies,
+                builder,
+                output,
+                |chunk| chunk.clone(),
+            )
+        }
+    }
+}
+
+fn compress_blocks(
+    token_type: TokenType,
+    block_size: usize,
+    builder: &EncoderBuilder,
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    info!("Compressing stdin in blocks of {block_size} bytes...");
+    let mut header = Header::new(token_type, 0);
+    header.flags |= FLAG_FRAMED | FLAG_UNKNOWN_LENGTH;
+    header.write_to(output)?;
+
+    let reader = io::stdin().lock();
+    match token_type {
+        TokenType::Chars => huffman::compress_blocks(
+            parser::read_lines(reader),
+            output,
+            block_size,
+            builder,
+            |line| line.chars().collect(),
+        ),
+        TokenType::Words => huffman::compress_blocks(
+            parser::read_lines(reader),
+            output,
+            block_size,
+            builder,
+            |line| line.split_inclusive(' ').map(String::from).collect(),
+        ),
+        TokenType::Bytes => huffman::compress_blocks(
+            parser::read_chunks(reader),
+            output,
+            block_size,
+            builder,
+            |chunk| chunk.clone(),
+        ),
+    }
+}
+
+/// Write the header for a framed body, once the first pass has read to the end of the input, then
+/// rewind the input for the second pass.
+fn write_framed_header(
+    token_type: TokenType,
+    reader: &mut (impl Read + Seek),
+    output: &mut impl Write,
+) -> io::Result<()> {
+    let mut header = Header::new(token_type, reader.stream_position()?);
+    header.flags |= FLAG_FRAMED;
+    header.write_to(output)?;
+    reader.rewind()
+}
+
+/// Write a codebook file: the header, then the MessagePack encoded codebook. Returns the ID of the
+/// codebook, which files compressed with it record.
+pub fn write_codebook<T: Serialize>(
+    header: &CodebookHeader,
+    codebook: &Codebook<T>,
+    output: &mut impl Write,
+) -> Result<u64, Error> {
+    header.write_to(output)?;
+    rmp_serde::encode::write(output, codebook)?;
+    Ok(container::codebook_id(codebook))
+}
+
+/// Decompress a whole compressed file, header and all, writing the text to `output`. Returns the
+/// length of the text.
+///
+/// `codebook` is the shared codebook file the input was compressed with, header and all, if any.
+/// Files compressed with a built-in model need nothing extra.
+pub fn decompress(
+    mut reader: impl Read,
+    codebook: Option<&[u8]>,
+    limits: &DecodeLimits,
+    output: &mut impl Write,
+) -> Result<u64, Error> {
+    let header = Header::read_from(&mut reader)?;
+
+    // Streamed input records its length at the end of the body instead, which is checked there.
+    let known_length = (header.flags & FLAG_UNKNOWN_LENGTH == 0).then_some(header.original_length);
+
+    if header.flags & FLAG_FRAMED != 0 {
+        let decoded_length = match header.token_type {
+            TokenType::Chars => huffman::decompress_framed(
+                reader,
+                output,
+                load_codebook(&header, codebook, limits)?,
+                limits,
+                |tokens: Vec<char>| tokens.into_iter().collect::<String>().into_bytes(),
+            )?,
+            TokenType::Words => huffman::decompress_framed(
+                reader,
+                output,
+                load_codebook(&header, codebook, limits)?,
+                limits,
+                |tokens: Vec<String>| tokens.join("").into_bytes(),
+            )?,
+            TokenType::Bytes => huffman::decompress_framed(
+                reader,
+                output,
+                load_codebook(&header, codebook, limits)?,
+                limits,
+                |tokens: Vec<u8>| tokens,
+            )?,
+        };
+        if let Some(original_length) = known_length {
+            huffman::check_length(original_length, decoded_length)?;
+        }
+        return Ok(decoded_length);
+    }
+
+    let data = match header.token_type {
+        TokenType::Chars => {
+            let compressed: CompressedData<char> = huffman::read_compressed(reader, limits)?;
+            let codebook = load_codebook(&header, codebook, limits)?;
+            huffman::decompress(compressed, codebook, limits, |tokens: Vec<char>| {
+                tokens.into_iter().collect::<String>().into_bytes()
+            })?
+        }
+        TokenType::Words => {
+            let compressed: CompressedData<String> = huffman::read_compressed(reader, limits)?;
+            let codebook = load_codebook(&header, codebook, limits)?;
+            huffman::decompress(compressed, codebook, limits, |tokens: Vec<String>| {
+                tokens.join("").into_bytes()
+            })?
+        }
+        TokenType::Bytes => {
+            let compressed: CompressedData<u8> = huffman::read_compressed(reader, limits)?;
+            let codebook = load_codebook(&header, codebook, limits)?;
+            huffman::decompress(compressed, codebook, limits, |tokens: Vec<u8>| tokens)?
+        }
+    };
+    if let Some(original_length) = known_length {
+        huffman::check_length(original_length, data.len() as u64)?;
+    }
+    output.write_all(&data)?;
+    Ok(data.len() as u64)
+}
+
+/// Load the shared codebook or built-in model a file was compressed with, if any, checking that it
+/// is the right one. `codebook` is the shared codebook file, header and all, if one was given, and
+/// is read within `limits`.
+pub fn load_codebook<T: Serialize + DeserializeOwned + Ord>(
+    header: &Header,
+    codebook: Option<&[u8]>,
+    limits: &DecodeLimits,
+) -> Result<Option<Codebook<T>>, Error> {
+    if let Some(model_id) = header.model_id {
+        let model = Model::from_id(model_id)
+            .filter(|model| model.token_type() == header.token_type)
+            .ok_or(FormatError::UnknownModel(model_id))?;
+        return Ok(Some(model.codebook()?));
+    }
+    let Some(codebook_id) = header.codebook_id else {
+        return Ok(None);
+    };
+    let mut reader = codebook.ok_or(FormatError::MissingCodebook(codebook_id))?;
+    if CodebookHeader::read_from(&mut reader)?.token_type != header.token_type {
+        return Err(FormatError::WrongCodebook(codebook_id).into());
+    }
+    let codebook: Codebook<T> = Codebook::read_from(reader, limits)?;
+    if container::codebook_id(&codebook) != codebook_id {
+        return Err(FormatError::WrongCodebook(codebook_id).into());
+    }
+    Ok(Some(codebook))
+}
+
+/// Decompress each named file, discarding the text, and write one line to `report` saying whether
+/// it decoded correctly, e.g. "a.huf: OK (200000 bytes)" or "b.huf: FAILED (checksum mismatch...)".
+///
+/// `open` opens a file by name, and failing to open one counts as a failure. Returns the number of
+/// files which failed, so that the caller can fail if there were any.
+pub fn test_files<R: Read>(
+    names: &[&str],
+    mut open: impl FnMut(&str) -> io::Result<R>,
+    codebook: Option<&[u8]>,
+    limits: &DecodeLimits,
+    report: &mut impl Write,
+) -> io::Result<usize> {
+    let mut failed = 0;
+    for &name in names {
+        let decompressed = open(name)
+            .map_err(Error::from)
+            .and_then(|reader| decompress(reader, codebook, limits, &mut io::sink()));
+        match decompressed {
+            Ok(length) => writeln!(report, "{name}: OK ({length} bytes)")?,
+            Err(e) => {
+                writeln!(report, "{name}: FAILED ({e})")?;
+                failed += 1;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+/// Compress or decompress each file in place, as gzip does.
+///
+/// The output of each file is written to the file named by `output_path`, which must not exist
+/// unless `--force` is given, and takes the permissions and modification time of the input. The
+/// input is removed once the output has been written, unless `--keep` or `--stdout` is given. A
+/// file which fails is reported to `report`, one line each, and the rest are still processed.
+/// Returns the number of files which failed.
+pub fn in_place(
+    options: &FileArgs,
+    output_path: impl Fn(&str) -> Result<String, &'static str>,
+    process: impl Fn(&str, &mut dyn Write) -> Result<(), Error>,
+    report: &mut impl Write,
+) -> io::Result<usize> {
+    let mut failed = 0;
+    for file in &options.files {
+        info!("Processing {file}...");
+        if let Err(e) = process_file(file, options, &output_path, &process) {
+            writeln!(report, "{file}: {e}")?;
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
+fn process_file(
+    file: &str,
+    options: &FileArgs,
+    output_path: impl Fn(&str) -> Result<String, &'static str>,
+    process: impl Fn(&str, &mut dyn Write) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let metadata = fs::metadata(file)?;
+    if !metadata.is_file() {
+        return Err(skipped("not a regular file"));
+    }
+    if options.stdout {
+        let mut output = BufWriter::new(io::stdout().lock());
+        process(file, &mut output)?;
+        return Ok(output.flush()?);
+    }
+
+    let output_path = output_path(file).map_err(skipped)?;
+    let mut open_options = fs::OpenOptions::new();
+    match options.force {
+        true => open_options.write(true).create(true).truncate(true),
+        false => open_options.write(true).create_new(true),
+    };
+    let output = open_options
+        .open(&output_path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => io::Error::new(
+                e.kind(),
+                format!("{output_path} already exists, use --force to overwrite it"),
+            ),
+            _ => e,
+        })?;
+
+    let written = (|| -> Result<(), Error> {
+        let mut writer = BufWriter::new(output);
+        process(file, &mut writer)?;
+        let output = writer.into_inner().map_err(|e| e.into_error())?;
+        output.set_permissions(metadata.permissions())?;
+        output.set_modified(metadata.modified()?)?;
+        Ok(output.sync_all()?)
+    })();
+    if let Err(e) = written {
+        // Leave nothing half written behind.
+        fs::remove_file(&output_path)?;
+        return Err(e);
+    }
+    info!("Wrote {output_path}");
+    if !options.keep {
+        fs::remove_file(file)?;
+    }
+    Ok(())
+}
+
+fn skipped(reason: &str) -> Error {
+    Error::Io(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{reason}, skipped"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{application::parser::TokenParser, encoding::huffman::EncoderBuilder};
+
+    fn compressed_file(text: &str) -> Vec<u8> {
+        let input = TokenParser::chars_from_reader(text.as_bytes()).unwrap();
+        let compressed = huffman::compress(
+            &input.lines,
+            input.token_frequencies,
+            &EncoderBuilder::new(),
+            |line| line.chars(),
+        )
+        .unwrap();
+        let mut file = Vec::new();
+        Header::new(TokenType::Chars, text.len() as u64)
+            .write_to(&mut file)
+            .unwrap();
+        rmp_serde::encode::write(&mut file, &compressed).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_files_are_reported_one_line_each() {
+        let text = "It was the best of times,\nit was the worst of times,\n";
+        let good = compressed_file(text);
+        let mut corrupt = good.clone();
+        // The checksum of the whole text is the last field of the body.
+        *corrupt.last_mut().unwrap() ^= 1;
+        let open = |name: &str| match name {
+            "good.huf" => Ok(&good[..]),
+            "corrupt.huf" => Ok(&corrupt[..]),
+            _ => Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+
+        let mut report = Vec::new();
+        let failed = test_files(
+            &["good.huf", "corrupt.huf", "missing.huf"],
+            open,
+            None,
+            &DecodeLimits::default(),
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(failed, 2);
+        let report = String::from_utf8(report).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], format!("good.huf: OK ({} bytes)", text.len()));
+        assert!(lines[1].starts_with("corrupt.huf: FAILED (checksum mismatch"));
+        assert!(lines[2].starts_with("missing.huf: FAILED ("));
+
+        let mut report = Vec::new();
+        let failed = test_files(
+            &["good.huf"],
+            open,
+            None,
+            &DecodeLimits::default(),
+            &mut report,
+        )
+        .unwrap();
+        assert_eq!(failed, 0);
+    }
+
+    fn in_place_options(files: &[&Path], force: bool) -> FileArgs {
+        FileArgs {
+            files: files
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect(),
+            stdout: false,
+            keep: false,
+            force,
+        }
+    }
+
+    fn huf_path(file: &str) -> Result<String, &'static str> {
+        container::compressed_path(file).ok_or("already has the .huf suffix")
+    }
+
+    /// Copy each file to its output, as though compressing it.
+    fn copy(file: &str, output: &mut dyn Write) -> Result<(), Error> {
+        output.write_all(&fs::read(file)?)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_place_replaces_the_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("a.txt");
+        fs::write(&input, "text").unwrap();
+
+        let mut report = Vec::new();
+        let options = in_place_options(&[&input], false);
+        let failed = in_place(&options, huf_path, copy, &mut report).unwrap();
+        assert_eq!(failed, 0);
+        assert!(report.is_empty());
+        assert!(!input.exists());
+        let output = dir.path().join("a.txt.huf");
+        assert_eq!(fs::read_to_string(output).unwrap(), "text");
+    }
+
+    #[test]
+    fn test_in_place_does_not_overwrite() {
+        let dir = tempfile::tempdir().unwrap();
+        let (input, output) = (dir.path().join("a.txt"), dir.path().join("a.txt.huf"));
+        fs::write(&input, "text").unwrap();
+        fs::write(&output, "existing").unwrap();
+
+        let mut report = Vec::new();
+        let options = in_place_options(&[&input], false);
+        let failed = in_place(&options, huf_path, copy, &mut report).unwrap();
+        assert_eq!(failed, 1);
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("already exists, use --force to overwrite it"));
+        assert_eq!(fs::read_to_string(&output).unwrap(), "existing");
+        assert_eq!(fs::read_to_string(&input).unwrap(), "text");
+
+        let options = in_place_options(&[&input], true);
+        let failed = in_place(&options, huf_path, copy, &mut io::sink()).unwrap();
+        assert_eq!(failed, 0);
+        assert_eq!(fs::read_to_string(&output).unwrap(), "text");
+    }
+
+    #[test]
+    fn test_in_place_keeps_the_input_when_writing_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a.txt"), dir.path().join("b.txt"));
+        fs::write(&a, "text").unwrap();
+        fs::write(&b, "text").unwrap();
+
+        // Fail partway through the first file; the second is still processed.
+        let fail_on_a = |file: &str, output: &mut dyn Write| {
+            copy(file, output)?;
+            match file.ends_with("a.txt") {
+                true => Err(Error::CorruptData(String::from("bad input"))),
+                false => Ok(()),
+            }
+        };
+        let mut report = Vec::new();
+        let options = in_place_options(&[&a, &b], false);
+        let failed = in_place(&options, huf_path, fail_on_a, &mut report).unwrap();
+        assert_eq!(failed, 1);
+        let report = String::from_utf8(report).unwrap();
+        assert_eq!(
+            report,
+            format!("{}: corrupt data: bad input\n", a.display())
+        );
+        assert_eq!(fs::read_to_string(&a).unwrap(), "text");
+        assert!(!dir.path().join("a.txt.huf").exists());
+        assert!(!b.exists());
+        assert!(dir.path().join("b.txt.huf").exists());
+    }
+}
@@ -1,4 +1,16 @@
 /// Module containing input parsing logic and CLI configuration.
 pub mod application;
+/// Module containing the archive format, which stores many files and directories in one file.
+pub mod archive;
+/// Module containing the self-describing file format written by the compressor.
+pub mod container;
 /// Module containing logic for encoding and decoding text.
 pub mod encoding;
+/// Module containing the error type returned by the library.
+pub mod error;
+/// Module containing the decompression and checking of whole compressed files, header and all.
+pub mod file;
+/// Module containing the pre-trained codebooks built into the compressor.
+pub mod models;
+
+pub use error::Error;
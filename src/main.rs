@@ -1,116 +1,646 @@
 use std::{
+    collections::HashMap,
     fs,
-    io::{self, BufReader, Write},
+    hash::Hash,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
 };
 #[macro_use]
 extern crate log;
 
+use clap::ValueEnum;
 use compressor::{
     application::{
-        cli::{Args, Mode, Parser, TokenType},
-        parser::TokenParser,
+        cli::{
+            ArchiveArgs, Args, Command, DecompressArgs, ExtractArgs, FileArgs, ListArgs, Parser,
+            StatsArgs, TokenType,
+        },
+        parser::{self, TokenParser},
     },
-    encoding::huffman::{self, CompressedData, HuffmanEncoder},
+    archive::{
+        compress_entry, extract_file, mode_string, select_entries, solid_codebook, utc_date_time,
+        walk_entries, ArchiveReader, ArchiveWriter, EntryKind,
+    },
+    container::{self, ArchiveHeader, CodebookHeader, Header, FLAG_FRAMED, FLAG_UNKNOWN_LENGTH},
+    encoding::huffman::{
+        summary::{self, BodySummary, CodebookSummary, Layout},
+        DecodeLimits, EncoderBuilder, Literal,
+    },
+    file,
+    models::Model,
+    Error,
 };
+use serde::{de::DeserializeOwned, Serialize};
 
-pub fn main() -> Result<(), Box<dyn std::error::Error>> {
+pub fn main() {
     let cli = Args::parse();
     env_logger::init();
 
-    let data = match cli.mode {
-        Mode::Compress => compress(cli.token_type, cli.in_file)?,
-        Mode::Decompress => decompress(cli.token_type, cli.in_file)?,
+    if let Err(e) = run(cli) {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
+}
+
+fn run(cli: Args) -> Result<(), Box<dyn std::error::Error>> {
+    match cli.subcommand() {
+        Command::Compress(args) if args.decompress => decompress_command(args.into())?,
+        Command::Compress(args) if !args.files.files.is_empty() => in_place(
+            &args.files,
+            |file| container::compressed_path(file).ok_or("already has the .huf suffix"),
+            |file, mut output| file::compress(&args.options, Some(Path::new(file)), &mut output),
+        )?,
+        Command::Compress(args) => {
+            let mut output = open_output(args.out_file.as_deref())?;
+            file::compress(
+                &args.options,
+                args.in_file.as_deref().map(Path::new),
+                &mut output,
+            )?;
+            output.flush()?;
+        }
+        Command::Decompress(args) => decompress_command(args)?,
+        Command::Test(args) => test(args.files, args.codebook, !args.no_verify)?,
+        Command::Info(args) => print_info(args.file, args.codebook, &mut io::stdout().lock())?,
+        Command::Train(args) => {
+            let mut output = open_output(args.out_file.as_deref())?;
+            let builder = EncoderBuilder::new().max_code_length(args.max_code_length);
+            train(
+                args.token_type,
+                args.files,
+                args.max_tokens,
+                &builder,
+                &mut output,
+            )?;
+            output.flush()?;
+        }
+        Command::Stats(args) => stats(args, &mut io::stdout().lock())?,
+        Command::Archive(args) => archive(args)?,
+        Command::Extract(args) => extract(args)?,
+        Command::List(args) => list(args, &mut io::stdout().lock())?,
     };
 
-    match cli.out_file {
+    info!("Done!");
+    Ok(())
+}
+
+fn open_output(out_file: Option<&str>) -> io::Result<Box<dyn Write>> {
+    Ok(match out_file {
         Some(s) => {
             info!("Writing to file: {s}");
-            fs::write(s, data)?;
+            Box::new(BufWriter::new(fs::File::create(s)?))
         }
         None => {
             info!("Writing to stdout.");
-            io::stdout().write(&data)?;
+            Box::new(BufWriter::new(io::stdout().lock()))
+        }
+    })
+}
+
+fn open_input(in_file: Option<&str>) -> io::Result<Box<dyn Read>> {
+    Ok(match in_file {
+        Some(s) => {
+            info!("Reading from file: {s}");
+            Box::new(BufReader::new(fs::File::open(s)?))
+        }
+        None => {
+            info!("Reading from stdin.");
+            Box::new(io::stdin().lock())
+        }
+    })
+}
+
+/// Count tokens across every file, or stdin, and write the codebook for the combined counts.
+///
+/// With `max_tokens`, only the most common tokens are kept, and the escape is counted in place of
+/// the rest.
+fn train(
+    token_type: TokenType,
+    files: Vec<String>,
+    max_tokens: Option<usize>,
+    builder: &EncoderBuilder,
+    output: &mut impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    info!("Counting tokens in {} files...", files.len());
+    let codebook_header = CodebookHeader::new(token_type);
+    let codebook_id = match token_type {
+        TokenType::Chars => {
+            let frequencies = count_corpus(&files, TokenParser::char_frequencies)?;
+            let (frequencies, builder) = limit_vocabulary(frequencies, max_tokens, builder);
+            file::write_codebook(
+                &codebook_header,
+                &builder.build(&frequencies)?.codebook,
+                output,
+            )?
+        }
+        TokenType::Words => {
+            let frequencies = count_corpus(&files, TokenParser::word_frequencies)?;
+            let (frequencies, builder) = limit_vocabulary(frequencies, max_tokens, builder);
+            file::write_codebook(
+                &codebook_header,
+                &builder.build(&frequencies)?.codebook,
+                output,
+            )?
+        }
+        TokenType::Bytes => {
+            let frequencies = count_corpus(&files, TokenParser::byte_frequencies)?;
+            let (frequencies, builder) = limit_vocabulary(frequencies, max_tokens, builder);
+            file::write_codebook(
+                &codebook_header,
+                &builder.build(&frequencies)?.codebook,
+                output,
+            )?
         }
+    };
+    info!("Wrote codebook {codebook_id:016x}");
+    Ok(())
+}
+
+fn limit_vocabulary<T: Hash + Eq + Ord>(
+    frequencies: HashMap<T, u64>,
+    max_tokens: Option<usize>,
+    builder: &EncoderBuilder,
+) -> (HashMap<T, u64>, EncoderBuilder) {
+    let Some(max_tokens) = max_tokens else {
+        return (frequencies, *builder);
+    };
+    let (frequencies, escaped) = parser::most_frequent(frequencies, max_tokens);
+    info!("Kept the {max_tokens} most common tokens, escaping {escaped} others");
+    (frequencies, builder.escape_count(escaped))
+}
+
+fn count_corpus<T: Hash + Eq>(
+    files: &[String],
+    count: impl Fn(Box<dyn BufRead>) -> Result<HashMap<T, u64>, Error>,
+) -> Result<HashMap<T, u64>, Box<dyn std::error::Error>> {
+    if files.is_empty() {
+        return Ok(count(Box::new(std::io::stdin().lock()))?);
     }
+    let mut frequencies = HashMap::new();
+    for file in files {
+        let reader = BufReader::new(fs::File::open(file)?);
+        parser::merge_frequencies(&mut frequencies, count(Box::new(reader))?)?;
+    }
+    Ok(frequencies)
+}
 
-    info!("Done!");
+fn decompress_command(args: DecompressArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let codebook = read_codebook(args.codebook.as_deref())?;
+    let limits = decode_limits(!args.no_verify);
+    if !args.files.files.is_empty() {
+        return in_place(
+            &args.files,
+            |file| container::decompressed_path(file).ok_or("does not have the .huf suffix"),
+            |file, mut output| {
+                file::decompress(
+                    open_input(Some(file))?,
+                    codebook.as_deref(),
+                    &limits,
+                    &mut output,
+                )?;
+                Ok(())
+            },
+        );
+    }
+    let mut output = open_output(args.out_file.as_deref())?;
+    file::decompress(
+        open_input(args.in_file.as_deref())?,
+        codebook.as_deref(),
+        &limits,
+        &mut output,
+    )?;
+    Ok(output.flush()?)
+}
+
+/// Compress or decompress each file in place, failing if any of them failed.
+fn in_place(
+    options: &FileArgs,
+    output_path: impl Fn(&str) -> Result<String, &'static str>,
+    process: impl Fn(&str, &mut dyn Write) -> Result<(), Error>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let failed = file::in_place(options, output_path, process, &mut io::stderr().lock())?;
+    if failed > 0 {
+        return Err(format!("{failed} of {} files failed", options.files.len()).into());
+    }
     Ok(())
 }
 
-fn compress(
-    token_type: TokenType,
+/// Limits for decoding untrusted input, checking the checksums unless `--no-verify` was given.
+fn decode_limits(verify_checksums: bool) -> DecodeLimits {
+    DecodeLimits {
+        verify_checksums,
+        ..DecodeLimits::default()
+    }
+}
+
+/// Read a shared codebook file, if one was given.
+fn read_codebook(codebook_file: Option<&str>) -> io::Result<Option<Vec<u8>>> {
+    codebook_file
+        .map(|codebook_file| {
+            info!("Reading codebook {codebook_file}...");
+            fs::read(codebook_file)
+        })
+        .transpose()
+}
+
+/// Decompress each file, or stdin, discarding the text, and print whether it decoded
+/// correctly. Fails if any of them did not.
+fn test(
+    files: Vec<String>,
+    codebook_file: Option<String>,
+    verify_checksums: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let codebook = read_codebook(codebook_file.as_deref())?;
+    let names: Vec<&str> = match files.is_empty() {
+        true => vec!["stdin"],
+        false => files.iter().map(String::as_str).collect(),
+    };
+    let failed = file::test_files(
+        &names,
+        |name| open_input((!files.is_empty()).then_some(name)),
+        codebook.as_deref(),
+        &decode_limits(verify_checksums),
+        &mut io::stdout().lock(),
+    )?;
+    if failed > 0 {
+        return Err(format!("{failed} of {} files failed the test", names.len()).into());
+    }
+    Ok(())
+}
+
+/// Print what a compressed file holds, read from its header and the structure of its body, without
+/// decoding any text.
+fn print_info(
     input_file: Option<String>,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    info!("Compressing text...");
-    match token_type {
-        TokenType::Chars => {
-            info!("Generating char tokens...");
-            let input_data = match input_file {
-                Some(s) => TokenParser::chars_from_reader(BufReader::new(fs::File::open(s)?)),
-                None => TokenParser::chars_from_reader(std::io::stdin().lock()),
-            };
+    codebook_file: Option<String>,
+    output: &mut impl Write,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let reader = open_input(input_file.as_deref())?;
+    let mut reader = CountingReader {
+        inner: reader,
+        count: 0,
+    };
+    let header = Header::read_from(&mut reader)?;
+    let codebook_file = codebook_file.as_deref();
+    let limits = DecodeLimits::default();
+
+    info!("Reading body...");
+    let (body, shared) = match header.token_type {
+        TokenType::Chars => summarize::<char>(&header, &mut reader, codebook_file, &limits)?,
+        TokenType::Words => summarize::<String>(&header, &mut reader, codebook_file, &limits)?,
+        TokenType::Bytes => summarize::<u8>(&header, &mut reader, codebook_file, &limits)?,
+    };
+    let original_length = match body.original_length {
+        Some(length) if header.flags & FLAG_UNKNOWN_LENGTH != 0 => length,
+        _ => header.original_length,
+    };
+    let compressed_length = reader.count;
+    let header_length = header.encoded_len() as u64;
+
+    writeln!(output, "format version:  {}", header.version)?;
+    writeln!(output, "token type:      {}", value_name(header.token_type))?;
+    writeln!(output, "layout:          {}", body.layout)?;
+    match body.lines {
+        Some(lines) => writeln!(output, "lines:           {lines}")?,
+        None => writeln!(output, "blocks:          {}", body.blocks)?,
+    }
+    writeln!(output, "original size:   {original_length} bytes")?;
+    writeln!(
+        output,
+        "compressed size: {compressed_length} bytes ({:.2}% of the original)",
+        percentage(compressed_length, original_length)
+    )?;
+    writeln!(output, "  header:        {header_length} bytes")?;
+    writeln!(
+        output,
+        "  body:          {} bytes",
+        compressed_length - header_length
+    )?;
+    writeln!(
+        output,
+        "encoded text:    {} bits ({:.2} bits per byte)",
+        body.bits,
+        body.bits as f64 / original_length.max(1) as f64
+    )?;
+
+    let codebook = match (header.model_id, header.codebook_id) {
+        (Some(model_id), _) => {
+            let name = Model::from_id(model_id).map_or_else(|| model_id.to_string(), value_name);
+            format!("built-in model {name}")
+        }
+        (None, Some(codebook_id)) if shared.is_none() => {
+            format!("shared {codebook_id:016x}, give --codebook to describe it")
+        }
+        (None, Some(codebook_id)) => format!("shared {codebook_id:016x}"),
+        (None, None) => match body.codebooks.len() {
+            1 => format!("embedded, {} bytes", body.codebooks[0].bytes),
+            n => format!(
+                "{n} embedded, {} bytes in total",
+                body.codebooks
+                    .iter()
+                    .map(|codebook| codebook.bytes)
+                    .sum::<usize>()
+            ),
+        },
+    };
+    writeln!(output, "codebook:        {codebook}")?;
+    let codebooks = shared.map_or(body.codebooks, |shared| vec![shared]);
+    if !codebooks.is_empty() {
+        let symbols: usize = codebooks.iter().map(|codebook| codebook.symbols).sum();
+        let max_length = codebooks.iter().map(|codebook| codebook.max_length).max();
+        let total_length: f64 = codebooks
+            .iter()
+            .map(|codebook| codebook.mean_length * codebook.symbols as f64)
+            .sum();
+        writeln!(
+            output,
+            "code lengths:    {symbols} symbols, max {} bits, mean {:.2} bits",
+            max_length.unwrap_or(0),
+            total_length / symbols.max(1) as f64
+        )?;
+    }
+
+    let blocks = match body.layout {
+        Layout::Lines => String::new(),
+        _ => format!(", {} of {} blocks", body.block_checksums, body.blocks),
+    };
+    writeln!(
+        output,
+        "checksums:       crc32 {:08x} of the input{blocks}, not verified (see `compressor test`)",
+        body.checksum
+    )?;
+    Ok(())
+}
+
+/// Summarize the body of a file, and the shared codebook or built-in model it was compressed with,
+/// if it is available.
+fn summarize<T: Serialize + DeserializeOwned + Ord>(
+    header: &Header,
+    reader: impl Read,
+    codebook_file: Option<&str>,
+    limits: &DecodeLimits,
+) -> Result<(BodySummary, Option<CodebookSummary>), Box<dyn std::error::Error>> {
+    let body = summary::summarize::<T, _>(reader, header.flags & FLAG_FRAMED != 0, limits)?;
+    if header.codebook_id.is_some() && codebook_file.is_none() {
+        return Ok((body, None));
+    }
+    let codebook = read_codebook(codebook_file)?;
+    let shared = match file::load_codebook::<T>(header, codebook.as_deref(), limits)? {
+        Some(codebook) => Some(CodebookSummary::of(&codebook)?),
+        None => None,
+    };
+    Ok((body, shared))
+}
 
-            info!("Performing Huffman Compression...");
-            let compressed =
-                huffman::compress(&input_data.lines, input_data.token_frequencies, |line| {
-                    line.chars()
-                });
+/// Name of a value as given on the command line, e.g. "english-chars".
+fn value_name(value: impl ValueEnum) -> String {
+    value
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+fn percentage(part: u64, whole: u64) -> f64 {
+    100.0 * part as f64 / whole.max(1) as f64
+}
+
+/// Reader which counts the bytes read through it.
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
 
-            info!("Encoding into MessagePack format...");
-            Ok(rmp_serde::encode::to_vec(&compressed)?)
+/// Count the tokens in a file, or stdin, and print how many bits a Huffman code built for them
+/// would take, along with the most common tokens.
+fn stats(args: StatsArgs, output: &mut impl Write) -> Result<(), Box<dyn std::error::Error>> {
+    let reader: Box<dyn BufRead> = match &args.file {
+        Some(s) => Box::new(BufReader::new(fs::File::open(s)?)),
+        None => Box::new(std::io::stdin().lock()),
+    };
+    let builder = EncoderBuilder::new().max_code_length(args.max_code_length);
+    info!("Counting tokens...");
+    match args.token_type {
+        TokenType::Chars => {
+            let frequencies = TokenParser::char_frequencies(reader)?;
+            print_stats(frequencies, &builder, args.top, output)
         }
         TokenType::Words => {
-            info!("Generating word tokens...");
-            let input_data = match input_file {
-                Some(s) => TokenParser::words_from_reader(BufReader::new(fs::File::open(s)?)),
-                None => TokenParser::words_from_reader(std::io::stdin().lock()),
-            };
+            let frequencies = TokenParser::word_frequencies(reader)?;
+            print_stats(frequencies, &builder, args.top, output)
+        }
+        TokenType::Bytes => {
+            let frequencies = TokenParser::byte_frequencies(reader)?;
+            print_stats(frequencies, &builder, args.top, output)
+        }
+    }
+}
 
-            info!("Performing Huffman Compression...");
-            let compressed =
-                huffman::compress(&input_data.lines, input_data.token_frequencies, |line| {
-                    line.split_inclusive(' ').map(|token| token.to_string())
-                });
+fn print_stats<T>(
+    frequencies: HashMap<T, u64>,
+    builder: &EncoderBuilder,
+    top: usize,
+    output: &mut impl Write,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    T: Hash + Eq + Ord + Clone + Send + Sync + Serialize + Literal,
+{
+    let encoder = builder.build(&frequencies)?;
+    let lengths: HashMap<&T, u8> = encoder
+        .codebook
+        .symbols
+        .iter()
+        .zip(encoder.codebook.lengths.iter().copied())
+        .collect();
 
-            info!("Encoding into MessagePack...");
-            Ok(rmp_serde::encode::to_vec(&compressed)?)
+    let tokens = parser::weighted_total(&frequencies, |_| 1)?;
+    let input_length =
+        parser::weighted_total(&frequencies, |token| token.to_literal().len() as u64)?;
+    let encoded_bits = parser::weighted_total(&frequencies, |token| u64::from(lengths[token]))?;
+    let entropy: f64 = frequencies
+        .values()
+        .map(|&count| {
+            let p = count as f64 / tokens as f64;
+            -p * p.log2()
+        })
+        .sum();
+    let codebook_length = rmp_serde::encode::to_vec(&encoder.codebook)?.len();
+
+    writeln!(output, "input size:      {input_length} bytes")?;
+    writeln!(output, "tokens:          {tokens}")?;
+    writeln!(output, "distinct tokens: {}", frequencies.len())?;
+    writeln!(output, "entropy:         {entropy:.2} bits per token")?;
+    writeln!(
+        output,
+        "huffman code:    {:.2} bits per token, {} bytes ({:.2}% of the input)",
+        encoded_bits as f64 / tokens.max(1) as f64,
+        encoded_bits.div_ceil(8),
+        percentage(encoded_bits.div_ceil(8), input_length)
+    )?;
+    writeln!(output, "codebook:        {codebook_length} bytes")?;
+
+    let mut most_common: Vec<(&T, u64)> = frequencies
+        .iter()
+        .map(|(token, &count)| (token, count))
+        .collect();
+    most_common.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    if !most_common.is_empty() && top > 0 {
+        writeln!(output, "most common:")?;
+    }
+    for (token, count) in most_common.into_iter().take(top) {
+        writeln!(
+            output,
+            "  {count:>10} ({:>5.2}%) {:>2} bits  {:?}",
+            percentage(count, tokens),
+            lengths[token],
+            String::from_utf8_lossy(&token.to_literal())
+        )?;
+    }
+    Ok(())
+}
+
+/// Compress files and directories into one archive. Each file is compressed on its own, as
+/// `compress_input` would, so any entry can be extracted without decoding the others.
+///
+/// With `--solid`, the tokens of every file are counted together first, and every file is
+/// compressed with the one codebook built from them, which is stored once in the archive.
+fn archive(args: ArchiveArgs) -> Result<(), Box<dyn std::error::Error>> {
+    // Don't archive the archive itself, if it is written inside one of the directories.
+    let out_path = match &args.out_file {
+        Some(out_file) => {
+            fs::File::create(out_file)?;
+            Some(fs::canonicalize(out_file)?)
         }
+        None => None,
+    };
+    let entries = walk_entries(&args.paths, out_path.as_deref())?;
+
+    let solid_codebook = match args.solid {
+        true => solid_codebook(&args.options, &entries)?,
+        false => None,
+    };
+    let output = open_output(args.out_file.as_deref())?;
+    let mut writer = match &solid_codebook {
+        Some(codebook) => ArchiveWriter::solid(output, codebook)?,
+        None => ArchiveWriter::new(output, &ArchiveHeader::new())?,
+    };
+    for (entry, path) in entries {
+        info!("Archiving {}...", entry.path);
+        let compressed = match entry.kind {
+            EntryKind::File => compress_entry(&args.options, solid_codebook.as_deref(), &path)
+                .map_err(|e| format!("{}: {e}", path.display()))?,
+            EntryKind::Directory => Vec::new(),
+        };
+        writer.add(entry, &compressed)?;
     }
+    writer.finish()?.flush()?;
+    Ok(())
 }
 
-fn decompress(
-    token_type: TokenType,
-    input_file: Option<String>,
-) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    info!("Decompressing text...");
-    match token_type {
-        TokenType::Chars => {
-            info!("Deserializing from MessagePack...");
-            let deserialized_data: CompressedData<char> = match input_file {
-                Some(s) => rmp_serde::decode::from_read(fs::File::open(s)?)?,
-                None => rmp_serde::decode::from_read(std::io::stdin().lock())?,
-            };
-
-            info!("Decoding text...");
-            Ok(HuffmanEncoder::decode(
-                deserialized_data.decoder,
-                &deserialized_data.data,
-                |tokens: Vec<char>| tokens.into_iter().collect(),
-            ))
+/// Restore the entries of an archive, or only the named ones and everything in them.
+fn extract(args: ExtractArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let limits = DecodeLimits::default();
+    let mut archive = ArchiveReader::open(BufReader::new(fs::File::open(&args.archive)?), &limits)?;
+    let entries = select_entries(archive.entries(), &args.entries)?;
+    let codebook = match archive.shared_codebook()? {
+        Some(mut reader) => {
+            let mut codebook = Vec::new();
+            reader.read_to_end(&mut codebook)?;
+            Some(codebook)
         }
-        TokenType::Words => {
-            info!("Deserializing from MessagePack...");
-            let deserialized_data: CompressedData<String> = match input_file {
-                Some(s) => rmp_serde::decode::from_read(fs::File::open(s)?)?,
-                None => rmp_serde::decode::from_read(std::io::stdin().lock())?,
-            };
+        None => read_codebook(args.codebook.as_deref())?,
+    };
+    let limits = decode_limits(!args.no_verify);
+    let root = Path::new(&args.directory);
+
+    if args.stdout {
+        let mut output = BufWriter::new(io::stdout().lock());
+        for entry in entries.iter().filter(|entry| entry.kind == EntryKind::File) {
+            info!("Extracting {}...", entry.path);
+            let reader = archive.open_entry(entry)?;
+            file::decompress(reader, codebook.as_deref(), &limits, &mut output)
+                .map_err(|e| format!("{}: {e}", entry.path))?;
+        }
+        return Ok(output.flush()?);
+    }
+
+    let mut directories = Vec::new();
+    for entry in &entries {
+        info!("Extracting {}...", entry.path);
+        let path = entry.path_under(root)?;
+        match entry.kind {
+            EntryKind::Directory => {
+                fs::create_dir_all(&path)?;
+                directories.push((entry, path));
+            }
+            EntryKind::File => {
+                let reader = archive.open_entry(entry)?;
+                extract_file(
+                    entry,
+                    reader,
+                    codebook.as_deref(),
+                    &limits,
+                    &path,
+                    args.force,
+                )
+                .map_err(|e| format!("{}: {e}", entry.path))?;
+            }
+        }
+    }
+    // Deepest first, so that restoring a directory doesn't stop its subdirectories from being
+    // restored.
+    for (entry, path) in directories.iter().rev() {
+        entry.restore_metadata(path)?;
+    }
+    Ok(())
+}
 
-            info!("Decoding text...");
-            Ok(HuffmanEncoder::decode(
-                deserialized_data.decoder,
-                &deserialized_data.data,
-                |tokens: Vec<String>| tokens.join(""),
-            ))
+/// Print one line for each entry in an archive: its permissions, original and compressed sizes,
+/// modification time and path, then the totals. The shared codebook of a solid archive is counted
+/// in the compressed total.
+fn list(args: ListArgs, output: &mut impl Write) -> Result<(), Box<dyn std::error::Error>> {
+    let limits = DecodeLimits::default();
+    let mut archive = ArchiveReader::open(BufReader::new(fs::File::open(&args.archive)?), &limits)?;
+    let (mut files, mut size, mut length) = (0, 0, 0);
+    if let Some(codebook) = archive.shared_codebook()? {
+        writeln!(
+            output,
+            "solid archive, shared codebook of {} bytes",
+            codebook.limit()
+        )?;
+        length += codebook.limit();
+    }
+    for entry in archive.entries() {
+        let (kind, suffix) = match entry.kind {
+            EntryKind::File => ('-', ""),
+            EntryKind::Directory => ('d', "/"),
+        };
+        writeln!(
+            output,
+            "{kind}{} {:>12} {:>12} {} {}{suffix}",
+            mode_string(entry.mode),
+            entry.size,
+            entry.length,
+            utc_date_time(entry.mtime),
+            entry.path
+        )?;
+        if entry.kind == EntryKind::File {
+            files += 1;
         }
+        size += entry.size;
+        length += entry.length;
     }
+    writeln!(
+        output,
+        "{files} files, {} directories, {size} bytes compressed to {length} ({:.1}%)",
+        archive.entries().len() - files,
+        percentage(length, size)
+    )?;
+    Ok(())
 }
@@ -0,0 +1,103 @@
+use crate::{
+    application::cli::TokenType,
+    container::CodebookHeader,
+    encoding::huffman::{Codebook, DecodeLimits},
+    Error,
+};
+use serde::de::DeserializeOwned;
+
+/// Pre-trained codebooks built into the compressor.
+///
+/// A file compressed with a model stores only the model's one byte ID, rather than a codebook, so
+/// short texts compress well. Tokens the model was not trained on are escaped.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Model {
+    /// Chars of English text, trained on test_data/Ulysses.txt.
+    EnglishChars,
+    /// The 4096 most common words of English text, trained on test_data/Ulysses.txt.
+    EnglishWords,
+}
+
+impl Model {
+    /// Every built-in model.
+    pub const ALL: [Model; 2] = [Model::EnglishChars, Model::EnglishWords];
+
+    /// ID stored in the header of files compressed with the model. IDs are never reused, so files
+    /// stay readable as models are added.
+    pub fn id(self) -> u8 {
+        match self {
+            Model::EnglishChars => 1,
+            Model::EnglishWords => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Model> {
+        Model::ALL.into_iter().find(|model| model.id() == id)
+    }
+
+    pub fn token_type(self) -> TokenType {
+        match self {
+            Model::EnglishChars => TokenType::Chars,
+            Model::EnglishWords => TokenType::Words,
+        }
+    }
+
+    /// The model's codebook file, as written by `compressor train`.
+    pub fn codebook_file(self) -> &'static [u8] {
+        match self {
+            Model::EnglishChars => include_bytes!("../models/english-chars.codebook"),
+            Model::EnglishWords => include_bytes!("../models/english-words.codebook"),
+        }
+    }
+
+    /// Decode the model's codebook, checking it as `Codebook::read_from` checks a codebook file.
+    /// Fails if `T` does not match the model's token type.
+    pub fn codebook<T: Ord + DeserializeOwned>(self) -> Result<Codebook<T>, Error> {
+        let mut file = self.codebook_file();
+        CodebookHeader::read_from(&mut file)?;
+        Codebook::read_from(file, &DecodeLimits::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::huffman::{HuffmanDecoder, HuffmanEncoder};
+
+    #[test]
+    fn test_models_match_their_token_type() {
+        for model in Model::ALL {
+            let mut file = model.codebook_file();
+            let header = CodebookHeader::read_from(&mut file).unwrap();
+            assert_eq!(header.token_type, model.token_type());
+            assert_eq!(Model::from_id(model.id()), Some(model));
+        }
+    }
+
+    #[test]
+    fn test_models_load_and_validate() {
+        for model in Model::ALL {
+            let loaded = match model.token_type() {
+                TokenType::Chars => model.codebook::<char>().map(drop),
+                TokenType::Words => model.codebook::<String>().map(drop),
+                TokenType::Bytes => model.codebook::<u8>().map(drop),
+            };
+            assert!(loaded.is_ok(), "{model:?} failed to load: {loaded:?}");
+        }
+        assert!(Model::EnglishWords.codebook::<char>().is_err());
+    }
+
+    #[test]
+    fn test_short_text_round_trip() {
+        let input = vec![String::from("Hello, Mr Bloom! Zyzzyva? 😆\n")];
+        let codebook: Codebook<String> = Model::EnglishWords.codebook().unwrap();
+        let encoder = HuffmanEncoder::from_codebook(codebook.clone());
+        let encoded = encoder
+            .encode(&input, |line| line.split_inclusive(' ').map(String::from))
+            .unwrap();
+
+        let decoder = HuffmanDecoder::from_codebook(codebook);
+        let decoded = decoder.decode(&encoded, |tokens: Vec<String>| tokens.join("").into_bytes());
+        assert_eq!(decoded.unwrap(), input[0].as_bytes());
+    }
+}